@@ -0,0 +1,63 @@
+-- objects copied by `sync-crates migrate`, so an interrupted migration can resume
+CREATE TABLE IF NOT EXISTS migrations
+(
+    source      TEXT    NOT NULL,
+    target      TEXT    NOT NULL,
+    key         TEXT    NOT NULL,
+    size        INTEGER NOT NULL,
+    sha256      TEXT    NOT NULL,
+    migrated_at INTEGER NOT NULL,
+    PRIMARY KEY (source, target, key)
+);
+
+-- crates Upyun was asked to download by `sync-crates fetch`, failed ones are submitted again
+CREATE TABLE IF NOT EXISTS fetches
+(
+    key          TEXT    NOT NULL PRIMARY KEY,
+    url          TEXT    NOT NULL,
+    task_id      TEXT    NOT NULL,
+    -- submitted, ok or failed
+    status       TEXT    NOT NULL,
+    description  TEXT,
+    submitted_at INTEGER NOT NULL,
+    finished_at  INTEGER
+);
+
+-- requests per object key, written by the server when `SYNC_DB` points here
+CREATE TABLE IF NOT EXISTS downloads
+(
+    key                TEXT    NOT NULL PRIMARY KEY,
+    count              INTEGER NOT NULL,
+    last_downloaded_at INTEGER NOT NULL
+);
+
+-- since when `downloads` counts every download, the server only counts when `DL_FORMAT`
+-- sends cargo through `/sync`, what reaches it as CDN misses says nothing of popularity,
+-- and when it last flushed, it flushes even when nothing was downloaded
+CREATE TABLE IF NOT EXISTS download_sources
+(
+    source     TEXT    NOT NULL PRIMARY KEY,
+    since      INTEGER NOT NULL,
+    flushed_at INTEGER NOT NULL
+);
+
+-- storage class of objects moved by `sync-crates tier`, and when each was first seen,
+-- which stands for the last download of objects never downloaded since
+CREATE TABLE IF NOT EXISTS tiers
+(
+    backend       TEXT    NOT NULL,
+    key           TEXT    NOT NULL,
+    storage_class TEXT    NOT NULL,
+    first_seen_at INTEGER NOT NULL,
+    changed_at    INTEGER,
+    PRIMARY KEY (backend, key)
+);
+
+-- uploads the server gave up on, copied over from another backend by `sync-crates retry`
+CREATE TABLE IF NOT EXISTS failed_uploads
+(
+    backend   TEXT    NOT NULL,
+    key       TEXT    NOT NULL,
+    failed_at INTEGER NOT NULL,
+    PRIMARY KEY (backend, key)
+);
@@ -1,17 +1,40 @@
-use clap::{App, Arg};
+use clap::{value_t, App, Arg, ArgMatches, SubCommand};
+use crates_io_cn::index_entry::crate_entries;
+use crates_io_cn::key_template::{dl_url, through_sync, KeyTemplate, TemplateError};
+use crates_io_cn::storage::{self, ObjectEntry, Replica, Storage, StorageClass, StorageError};
+#[cfg(feature = "upyun")]
+use crates_io_cn::{
+    index_entry::{all_entries, upstream_url},
+    storage::UpyunStorage,
+    upyun::{FetchTask, MAX_FETCH_TASKS},
+};
 use directories::UserDirs;
+use futures::{future, stream, StreamExt};
+use sha2::{Digest, Sha256};
+use sqlite::{Connection, State};
 use std::{
-    fs,
+    collections::{HashMap, HashSet},
+    env, fs,
     io::{self, Read, Write},
     path::{Path, PathBuf},
-    process::{self, exit}
+    process::{self, exit},
+    time::{Duration, SystemTime, UNIX_EPOCH},
 };
 
 const NAME: &str = ".crates-io";
+const COPY_ATTEMPTS: usize = 3;
+/// Days an archived object stays readable after a restore for moving it back to standard
+const TIER_RESTORE_DAYS: u32 = 1;
+/// Seconds without a flush of download counts after which the server starts counting over
+const STATS_STALE_AFTER: i64 = 60 * 60;
+/// Stop polling fetch tasks after this long, the next run collects the rest
+#[cfg(feature = "upyun")]
+const FETCH_WAIT: Duration = Duration::from_secs(3600);
 
 struct LockGuard(PathBuf);
 
 fn main() {
+    dotenv::dotenv().ok();
     let user_dirs = UserDirs::new().expect("cannot locate user directories");
     let default_path = user_dirs.home_dir().join(NAME);
     if !default_path.exists() {
@@ -23,7 +46,7 @@ fn main() {
     }
     let _guard = LockGuard(default_path.clone());
     let default_db = default_path.join("db");
-    let matches = App::new("crates.io sync")
+    let app = App::new("crates.io sync")
         .arg(
             Arg::with_name("db")
                 .short("d")
@@ -33,12 +56,950 @@ fn main() {
                 .default_value_os(default_db.as_os_str())
                 .takes_value(true),
         )
-        .get_matches();
+        .subcommand(
+            SubCommand::with_name("migrate")
+                .about("copy every object from one storage backend to another")
+                .arg(
+                    Arg::with_name("from")
+                        .long("from")
+                        .value_name("BACKEND")
+                        .help("source backend: upyun, obs or local")
+                        .required(true)
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("to")
+                        .long("to")
+                        .value_name("BACKEND")
+                        .help("target backend: upyun, obs or local")
+                        .required(true)
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("jobs")
+                        .short("j")
+                        .long("jobs")
+                        .value_name("N")
+                        .help("objects copied concurrently")
+                        .default_value("16")
+                        .takes_value(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("tier")
+                .about("move objects not downloaded for a while to a cheaper storage class")
+                .arg(
+                    Arg::with_name("backend")
+                        .long("backend")
+                        .value_name("BACKEND")
+                        .help("backend to tier")
+                        .default_value("obs")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("days")
+                        .long("days")
+                        .value_name("N")
+                        .help("days without downloads before an object is moved")
+                        .default_value("90")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("class")
+                        .long("class")
+                        .value_name("CLASS")
+                        .help("storage class for unpopular objects: warm or cold")
+                        .default_value("cold")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("dry-run")
+                        .long("dry-run")
+                        .help("only print what would be moved"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("purge")
+                .about("purge crates from the CDN, by object url if DL_FORMAT points at /sync")
+                .arg(
+                    Arg::with_name("backend")
+                        .long("backend")
+                        .value_name("BACKEND")
+                        .help("backend whose CDN serves DL_FORMAT")
+                        .default_value("upyun")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("index")
+                        .long("index")
+                        .value_name("DIR")
+                        .help("checkout of crates.io-index")
+                        .required(true)
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("crates")
+                        .value_name("CRATE[@VERSION]")
+                        .help("crates to purge, every version if none is given")
+                        .required(true)
+                        .multiple(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("retry")
+                .about("copy crates the server failed to upload from another backend")
+                .arg(
+                    Arg::with_name("from")
+                        .long("from")
+                        .value_name("BACKEND")
+                        .help("backend holding the crates: upyun, obs or local")
+                        .required(true)
+                        .takes_value(true),
+                ),
+        );
+    #[cfg(feature = "upyun")]
+    let app = app.subcommand(
+        SubCommand::with_name("fetch")
+            .about("have Upyun download every crate in the index from static.crates.io")
+            .arg(
+                Arg::with_name("index")
+                    .long("index")
+                    .value_name("DIR")
+                    .help("checkout of crates.io-index")
+                    .required(true)
+                    .takes_value(true),
+            )
+            .arg(
+                Arg::with_name("notify-url")
+                    .long("notify-url")
+                    .value_name("URL")
+                    .help("where Upyun posts the result of each task")
+                    .takes_value(true),
+            )
+            .arg(
+                Arg::with_name("interval")
+                    .long("interval")
+                    .value_name("SECONDS")
+                    .help("seconds between polls for results")
+                    .default_value("10")
+                    .takes_value(true),
+            )
+            .arg(
+                Arg::with_name("jobs")
+                    .short("j")
+                    .long("jobs")
+                    .value_name("N")
+                    .help("objects checked concurrently")
+                    .default_value("16")
+                    .takes_value(true),
+            ),
+    );
+    let matches = app.get_matches();
     let db_path = Path::new(matches.value_of("db").unwrap());
     let conn = sqlite::open(&db_path).expect("cannot open db");
     conn.execute(include_str!("init.sql")).expect("cannot init db");
 
-    println!("{}", matches.value_of("db").unwrap());
+    let succeeded = match matches.subcommand() {
+        ("migrate", Some(args)) => migrate(&conn, args),
+        ("tier", Some(args)) => tier(&conn, args),
+        ("purge", Some(args)) => purge(args),
+        ("retry", Some(args)) => retry(&conn, args),
+        #[cfg(feature = "upyun")]
+        ("fetch", Some(args)) => fetch(&conn, args),
+        _ => {
+            println!("{}", matches.value_of("db").unwrap());
+            true
+        }
+    };
+    drop(_guard);
+    if !succeeded {
+        exit(1)
+    }
+}
+
+/// Copy objects missing from the migration record, returns whether all of them were copied
+fn migrate(conn: &Connection, args: &ArgMatches) -> bool {
+    let from = args.value_of("from").unwrap();
+    let to = args.value_of("to").unwrap();
+    let jobs = value_t!(args, "jobs", usize).unwrap_or_else(|e| e.exit());
+    let (source, target) = match (storage::from_env(from), storage::from_env(to)) {
+        (Ok(source), Ok(target)) => (source, target),
+        (Err(e), _) | (_, Err(e)) => {
+            eprintln!("{}", e);
+            return false;
+        }
+    };
+    let done = migrated(conn, from, to).expect("cannot read migration progress");
+    println!(
+        "{} objects already migrated from {} to {}",
+        done.len(),
+        from,
+        to
+    );
+
+    // uploads keep within the limits of the target backend, as they do in the server
+    let target = Replica::new(target);
+    let runtime = tokio::runtime::Runtime::new().expect("cannot start runtime");
+    runtime.block_on(async {
+        let (source, target) = (&*source, &target);
+        let mut copies = source
+            .list()
+            .filter(|entry| future::ready(!matches!(entry, Ok(entry) if done.contains(&entry.key))))
+            .map(|entry| async move {
+                match entry {
+                    Ok(entry) => match copy(source, target, &entry).await {
+                        Ok(checksum) => Ok((entry, checksum)),
+                        Err(e) => Err((entry.key, e)),
+                    },
+                    Err(e) => Err(("listing".to_string(), e)),
+                }
+            })
+            .buffer_unordered(jobs);
+        let (mut copied, mut failed) = (0, 0);
+        while let Some(result) = copies.next().await {
+            match result {
+                Ok((entry, checksum)) => {
+                    record(conn, from, to, &entry, &checksum).expect("cannot record progress");
+                    copied += 1;
+                    if copied % 1000 == 0 {
+                        println!("{} objects copied", copied);
+                    }
+                }
+                Err((key, e)) => {
+                    eprintln!("{}: {}", key, e);
+                    failed += 1;
+                }
+            }
+        }
+        println!("{} objects copied, {} failed", copied, failed);
+        failed == 0
+    })
+}
+
+/// Copy one object, checking its size and that the target reads back the same sha256,
+/// returns the sha256
+async fn copy(
+    source: &dyn Storage,
+    target: &Replica,
+    entry: &ObjectEntry,
+) -> Result<String, StorageError> {
+    let mut attempt = 1;
+    loop {
+        match try_copy(source, target, entry).await {
+            Err(e) if e.is_retryable() && attempt < COPY_ATTEMPTS => {
+                attempt += 1;
+                tokio::time::sleep(Duration::from_secs(1)).await;
+            }
+            result => return result,
+        }
+    }
+}
+
+async fn try_copy(
+    source: &dyn Storage,
+    target: &Replica,
+    entry: &ObjectEntry,
+) -> Result<String, StorageError> {
+    let content = source.get(&entry.key).await?;
+    if content.len() as u64 != entry.size {
+        return Err(StorageError::Checksum {
+            key: entry.key.clone(),
+            expected: format!("{} bytes", entry.size),
+            actual: format!("{} bytes", content.len()),
+        });
+    }
+    let checksum = format!("{:x}", Sha256::digest(&content));
+    // don't spread a rotten object, content addressed blobs are named by their checksum
+    // and others carry it as metadata when the server uploaded them
+    let name = entry.key.rsplit('/').next().unwrap_or_default();
+    let expected = if is_sha256(name) {
+        Some(name.to_string())
+    } else {
+        source.head(&entry.key).await?.and_then(|meta| meta.sha256)
+    };
+    if matches!(expected, Some(ref expected) if *expected != checksum) {
+        return Err(StorageError::Checksum {
+            key: entry.key.clone(),
+            expected: expected.unwrap_or_default(),
+            actual: checksum,
+        });
+    }
+    let length = content.len();
+    let body = Box::pin(stream::iter(vec![Ok(content)]));
+    target
+        .put(&entry.key, body, Some(length), &checksum)
+        .await?;
+    // sent with a Content-MD5, what is left to check is that it all got there
+    match target.storage.head(&entry.key).await? {
+        Some(meta) if meta.size == length as u64 && meta.sha256.as_deref() == Some(&*checksum) => {
+            Ok(checksum)
+        }
+        stored => Err(StorageError::Checksum {
+            key: entry.key.clone(),
+            expected: format!("{} bytes with sha256 {}", length, checksum),
+            actual: match stored {
+                Some(meta) => format!("{} bytes with sha256 {:?}", meta.size, meta.sha256),
+                None => "nothing".to_string(),
+            },
+        }),
+    }
+}
+
+/// Copy every failed upload the server recorded to its backend from `--from`,
+/// returns whether all of them were copied
+fn retry(conn: &Connection, args: &ArgMatches) -> bool {
+    let from = args.value_of("from").unwrap();
+    let source = match storage::from_env(from) {
+        Ok(source) => source,
+        Err(e) => {
+            eprintln!("{}", e);
+            return false;
+        }
+    };
+    let failures = failed_uploads(conn).expect("cannot read failed uploads");
+    println!("{} failed uploads to retry", failures.len());
+
+    let runtime = tokio::runtime::Runtime::new().expect("cannot start runtime");
+    runtime.block_on(async {
+        let source = &*source;
+        let mut targets: HashMap<String, Replica> = HashMap::new();
+        let (mut copied, mut failed) = (0, 0);
+        for (backend, key) in failures {
+            if backend == from {
+                eprintln!("{}: failed on {} itself", key, from);
+                failed += 1;
+                continue;
+            }
+            if !targets.contains_key(&backend) {
+                match storage::from_env(&backend) {
+                    Ok(target) => {
+                        targets.insert(backend.clone(), Replica::new(target));
+                    }
+                    Err(e) => {
+                        eprintln!("{}: {}", backend, e);
+                        failed += 1;
+                        continue;
+                    }
+                }
+            }
+            let target = &targets[&backend];
+            let result = match source.head(&key).await {
+                Ok(Some(meta)) => {
+                    let entry = ObjectEntry {
+                        key: key.clone(),
+                        size: meta.size,
+                    };
+                    copy(source, target, &entry)
+                        .await
+                        .map(drop)
+                        .map_err(|e| e.to_string())
+                }
+                Ok(None) => Err(format!("not in {}", from)),
+                Err(e) => Err(e.to_string()),
+            };
+            match result {
+                Ok(()) => {
+                    forget_failed_upload(conn, &backend, &key).expect("cannot record progress");
+                    copied += 1;
+                }
+                Err(e) => {
+                    eprintln!("{} to {}: {}", key, backend, e);
+                    failed += 1;
+                }
+            }
+        }
+        println!("{} objects copied, {} failed", copied, failed);
+        failed == 0
+    })
+}
+
+fn is_sha256(name: &str) -> bool {
+    name.len() == 64 && name.chars().all(|c| matches!(c, '0'..='9' | 'a'..='f'))
+}
+
+/// Move objects not downloaded for `days` to `class` and those downloaded since back to
+/// standard, driven by the `downloads` the server records, returns whether all moves succeeded
+///
+/// Objects never downloaded count from the first time they were seen here.
+fn tier(conn: &Connection, args: &ArgMatches) -> bool {
+    let backend = args.value_of("backend").unwrap();
+    let days = value_t!(args, "days", i64).unwrap_or_else(|e| e.exit());
+    let dry_run = args.is_present("dry-run");
+    let (storage, class) = match (
+        storage::from_env(backend),
+        args.value_of("class").unwrap().parse::<StorageClass>(),
+    ) {
+        (Ok(storage), Ok(class)) => (storage, class),
+        (Err(e), _) | (_, Err(e)) => {
+            eprintln!("{}", e);
+            return false;
+        }
+    };
+    let now = unix_now();
+    let cutoff = now - days * 24 * 3600;
+    // stats of CDN misses only would send the most popular crates to the cheapest class
+    match counted_since(conn).expect("cannot read download stats") {
+        Some((_, flushed_at)) if flushed_at < now - STATS_STALE_AFTER => {
+            eprintln!(
+                "download counts last flushed {} hours ago, is the server with SYNC_DB running?",
+                (now - flushed_at) / 3600
+            );
+            return false;
+        }
+        Some((since, _)) if since <= cutoff => (),
+        Some((since, _)) => {
+            eprintln!(
+                "every download counted for {} days only, fewer than --days",
+                (now - since) / (24 * 3600)
+            );
+            return false;
+        }
+        None => {
+            eprintln!(
+                "downloads were never all counted, point DL_FORMAT at /sync with SYNC_DB set"
+            );
+            return false;
+        }
+    }
+    let downloads = last_downloads(conn).expect("cannot read download stats");
+    let tiers = tiers(conn, backend).expect("cannot read storage classes");
+
+    let runtime = tokio::runtime::Runtime::new().expect("cannot start runtime");
+    runtime.block_on(async {
+        let mut objects = storage.list();
+        let (mut moved, mut failed) = (0, 0);
+        let mut new_keys = vec![];
+        while let Some(entry) = objects.next().await {
+            let key = match entry {
+                Ok(entry) => entry.key,
+                Err(e) => {
+                    eprintln!("listing: {}", e);
+                    failed += 1;
+                    break;
+                }
+            };
+            let (current, first_seen) = match tiers.get(&key) {
+                Some(tier) => *tier,
+                None => {
+                    new_keys.push(key.clone());
+                    (StorageClass::Standard, now)
+                }
+            };
+            let last = downloads.get(&key).copied().unwrap_or(first_seen);
+            let wanted = if last < cutoff {
+                class
+            } else {
+                StorageClass::Standard
+            };
+            if wanted == current {
+                continue;
+            }
+            if dry_run {
+                println!("{}: {} -> {}", key, current.as_str(), wanted.as_str());
+                continue;
+            }
+            match storage.set_storage_class(&key, wanted).await {
+                Ok(()) => {
+                    record_tier(conn, backend, &key, wanted, first_seen)
+                        .expect("cannot record storage class");
+                    moved += 1;
+                }
+                Err(e @ StorageError::Unsupported(_)) => {
+                    eprintln!("{}: {}", backend, e);
+                    return false;
+                }
+                // archived objects can only change class once restored
+                Err(e) if current == StorageClass::Cold => {
+                    eprintln!("{}: {}, restore it for the next run", key, e);
+                    if let Err(e) = storage.restore(&key, TIER_RESTORE_DAYS).await {
+                        eprintln!("{}: {}", key, e);
+                    }
+                    failed += 1;
+                }
+                Err(e) => {
+                    eprintln!("{}: {}", key, e);
+                    failed += 1;
+                }
+            }
+        }
+        if !dry_run {
+            record_first_seen(conn, backend, &new_keys, now).expect("cannot record objects");
+        }
+        println!(
+            "{} objects moved, {} failed, {} seen for the first time",
+            moved,
+            failed,
+            new_keys.len()
+        );
+        failed == 0
+    })
+}
+
+/// Purge the urls cargo downloads the given crates from, following `DL_FORMAT`,
+/// returns whether it succeeded
+fn purge(args: &ArgMatches) -> bool {
+    let dl = match env::var("DL_FORMAT") {
+        Ok(dl) => dl,
+        Err(_) => {
+            eprintln!("DL_FORMAT is not set");
+            return false;
+        }
+    };
+    let storage = match storage::from_env(args.value_of("backend").unwrap()) {
+        Ok(storage) => storage,
+        Err(e) => {
+            eprintln!("{}", e);
+            return false;
+        }
+    };
+    // through `/sync` cargo is redirected to the CDN of the bucket, which caches by key
+    let template = match key_template() {
+        Ok(template) => Some(template).filter(|_| through_sync(&dl)),
+        Err(e) => {
+            eprintln!("{}", e);
+            return false;
+        }
+    };
+    let root = Path::new(args.value_of("index").unwrap());
+    let mut urls = vec![];
+    for spec in args.values_of("crates").unwrap() {
+        let mut parts = spec.splitn(2, '@');
+        let name = parts.next().unwrap_or_default();
+        let version = parts.next();
+        let entries = match crate_entries(root, name) {
+            Ok((entries, skipped)) => {
+                if skipped > 0 {
+                    eprintln!("{}: {} invalid index lines skipped", name, skipped);
+                }
+                entries
+            }
+            Err(e) => {
+                eprintln!("{}: {}", name, e);
+                return false;
+            }
+        };
+        let found = entries
+            .iter()
+            .filter(|entry| match version {
+                Some(version) => entry.vers == version,
+                None => true,
+            })
+            .map(|entry| match &template {
+                Some(template) => storage.cdn_url(&template.render(entry)),
+                None => Some(dl_url(&dl, entry)),
+            })
+            .collect::<Option<Vec<_>>>();
+        let found = match found {
+            Some(found) => found,
+            None => {
+                eprintln!("DL_FORMAT points at /sync but the backend has no CDN domain");
+                return false;
+            }
+        };
+        if found.is_empty() {
+            eprintln!("{} is not in the index", spec);
+            return false;
+        }
+        urls.extend(found);
+    }
+
+    let runtime = tokio::runtime::Runtime::new().expect("cannot start runtime");
+    match runtime.block_on(storage.purge(&urls)) {
+        Ok(()) => {
+            for url in &urls {
+                println!("{}", url);
+            }
+            println!("{} urls purged", urls.len());
+            true
+        }
+        Err(e) => {
+            eprintln!("{}", e);
+            false
+        }
+    }
+}
+
+/// Object keys as the server renders them from `STORAGE_KEY_TEMPLATE`
+fn key_template() -> Result<KeyTemplate, TemplateError> {
+    KeyTemplate::new(
+        env::var("STORAGE_KEY_TEMPLATE").unwrap_or_else(|_| "{crate}/{version}".to_string()),
+    )
+}
+
+fn unix_now() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs() as i64
+}
+
+/// Submit a fetch task for every crate not stored yet and wait for the results,
+/// returns whether all of them were fetched and verified
+#[cfg(feature = "upyun")]
+fn fetch(conn: &Connection, args: &ArgMatches) -> bool {
+    let interval =
+        Duration::from_secs(value_t!(args, "interval", u64).unwrap_or_else(|e| e.exit()));
+    let jobs = value_t!(args, "jobs", usize).unwrap_or_else(|e| e.exit());
+    let notify_url = args.value_of("notify-url");
+    let template = match key_template() {
+        Ok(template) => template,
+        Err(e) => {
+            eprintln!("{}", e);
+            return false;
+        }
+    };
+    let upyun = match UpyunStorage::from_env() {
+        Ok(upyun) => upyun,
+        Err(e) => {
+            eprintln!("{}", e);
+            return false;
+        }
+    };
+    let (entries, skipped) =
+        all_entries(Path::new(args.value_of("index").unwrap())).expect("cannot read index");
+    if skipped > 0 {
+        eprintln!("{} invalid index lines skipped", skipped);
+    }
+    let mut skip = fetched(conn).expect("cannot read fetch progress");
+    let mut running = running_fetches(conn).expect("cannot read fetch progress");
+    skip.extend(running.iter().map(|(_, key)| key.clone()));
+    let mut cksums = HashMap::new();
+    let candidates: Vec<(String, String)> = entries
+        .iter()
+        .filter_map(|entry| {
+            let key = template.render(entry);
+            cksums.insert(key.clone(), entry.cksum.clone());
+            if !skip.insert(key.clone()) {
+                return None;
+            }
+            Some((key, upstream_url(&entry.name, &entry.vers)))
+        })
+        .collect();
+
+    let runtime = tokio::runtime::Runtime::new().expect("cannot start runtime");
+    runtime.block_on(async {
+        let (upyun, cksums) = (&upyun, &cksums);
+        // tasks overwrite, objects the server uploaded and verified are left alone
+        let mut checks = stream::iter(candidates)
+            .map(|(key, url)| async move {
+                let stored = matches!(
+                    upyun.head(&key).await,
+                    Ok(Some(meta)) if meta.sha256.as_ref() == cksums.get(&key)
+                );
+                (key, url, stored)
+            })
+            .buffer_unordered(jobs);
+        let mut tasks = vec![];
+        let mut stored = 0;
+        while let Some((key, url, is_stored)) = checks.next().await {
+            if is_stored {
+                record_stored(conn, &key, &url).expect("cannot record progress");
+                stored += 1;
+            } else {
+                tasks.push((key.clone(), FetchTask::new(url, &key)));
+            }
+        }
+        println!(
+            "{} crates already stored, {} to fetch, {} tasks still running",
+            stored,
+            tasks.len(),
+            running.len()
+        );
+
+        let (mut fetched, mut failed) = (0, 0);
+        for batch in tasks.chunks(MAX_FETCH_TASKS) {
+            let fetch_tasks: Vec<FetchTask> = batch.iter().map(|(_, task)| task.clone()).collect();
+            match upyun.fetch(&fetch_tasks, notify_url).await {
+                Ok(ids) => {
+                    for (id, (key, task)) in ids.into_iter().zip(batch) {
+                        record_submitted(conn, key, &task.url, &id)
+                            .expect("cannot record progress");
+                        running.push((id, key.clone()));
+                    }
+                }
+                Err(e) => {
+                    eprintln!("submit {} tasks: {}", batch.len(), e);
+                    failed += batch.len();
+                }
+            }
+        }
+        let start = tokio::time::Instant::now();
+        while !running.is_empty() && start.elapsed() < FETCH_WAIT {
+            tokio::time::sleep(interval).await;
+            let mut still_running = vec![];
+            for batch in running.chunks(MAX_FETCH_TASKS) {
+                let ids: Vec<String> = batch.iter().map(|(id, _)| id.clone()).collect();
+                let results = match upyun.fetch_results(&ids).await {
+                    Ok(results) => results,
+                    Err(e) => {
+                        eprintln!("poll {} tasks: {}", ids.len(), e);
+                        still_running.extend_from_slice(batch);
+                        continue;
+                    }
+                };
+                let finished = batch.iter().filter_map(|(id, key)| match results.get(id) {
+                    Some(Some(result)) => Some(async move {
+                        let verified = if !result.is_success() {
+                            Err(format!("{} {}", result.status_code, result.description))
+                        } else {
+                            match cksums.get(key) {
+                                Some(cksum) => verify_fetched(upyun, key, cksum).await,
+                                None => Err("no longer in the index".to_string()),
+                            }
+                        };
+                        (id, key, verified)
+                    }),
+                    _ => {
+                        still_running.push((id.clone(), key.clone()));
+                        None
+                    }
+                });
+                for (id, key, verified) in future::join_all(finished).await {
+                    match verified {
+                        Ok(()) => {
+                            record_finished(conn, id, true, "OK").expect("cannot record progress");
+                            fetched += 1;
+                        }
+                        Err(e) => {
+                            record_finished(conn, id, false, &e).expect("cannot record progress");
+                            eprintln!("{}: {}", key, e);
+                            failed += 1;
+                        }
+                    }
+                }
+            }
+            running = still_running;
+        }
+        println!(
+            "{} crates fetched, {} failed, {} still running",
+            fetched,
+            failed,
+            running.len()
+        );
+        failed == 0 && running.is_empty()
+    })
+}
+
+/// Check a fetched object against the index, and tag it with its SHA-256 as uploads are
+#[cfg(feature = "upyun")]
+async fn verify_fetched(upyun: &UpyunStorage, key: &str, cksum: &str) -> Result<(), String> {
+    let content = upyun.get(key).await.map_err(|e| e.to_string())?;
+    let size = match upyun.head(key).await.map_err(|e| e.to_string())? {
+        Some(meta) => meta.size,
+        None => return Err("missing after fetch".to_string()),
+    };
+    let checksum = format!("{:x}", Sha256::digest(&content));
+    if size != content.len() as u64 || checksum != cksum {
+        return Err(format!(
+            "{} bytes read of {} with sha256 {}, index has {}",
+            content.len(),
+            size,
+            checksum,
+            cksum
+        ));
+    }
+    upyun
+        .set_sha256(key, cksum)
+        .await
+        .map_err(|e| e.to_string())
+}
+
+fn migrated(conn: &Connection, source: &str, target: &str) -> sqlite::Result<HashSet<String>> {
+    let mut statement =
+        conn.prepare("SELECT key FROM migrations WHERE source = ? AND target = ?")?;
+    statement.bind(1, source)?;
+    statement.bind(2, target)?;
+    let mut keys = HashSet::new();
+    while let State::Row = statement.next()? {
+        keys.insert(statement.read::<String>(0)?);
+    }
+    Ok(keys)
+}
+
+fn record(
+    conn: &Connection,
+    source: &str,
+    target: &str,
+    entry: &ObjectEntry,
+    checksum: &str,
+) -> sqlite::Result<()> {
+    let mut statement = conn.prepare(
+        "INSERT OR REPLACE INTO migrations (source, target, key, size, sha256, migrated_at) \
+         VALUES (?, ?, ?, ?, ?, strftime('%s', 'now'))",
+    )?;
+    statement.bind(1, source)?;
+    statement.bind(2, target)?;
+    statement.bind(3, entry.key.as_str())?;
+    statement.bind(4, entry.size as i64)?;
+    statement.bind(5, checksum)?;
+    statement.next()?;
+    Ok(())
+}
+
+/// Keys fetched successfully
+#[cfg(feature = "upyun")]
+fn fetched(conn: &Connection) -> sqlite::Result<HashSet<String>> {
+    let mut statement = conn.prepare("SELECT key FROM fetches WHERE status = 'ok'")?;
+    let mut keys = HashSet::new();
+    while let State::Row = statement.next()? {
+        keys.insert(statement.read::<String>(0)?);
+    }
+    Ok(keys)
+}
+
+/// Task ids and keys of tasks submitted by an earlier run without a result yet
+#[cfg(feature = "upyun")]
+fn running_fetches(conn: &Connection) -> sqlite::Result<Vec<(String, String)>> {
+    let mut statement =
+        conn.prepare("SELECT task_id, key FROM fetches WHERE status = 'submitted'")?;
+    let mut tasks = vec![];
+    while let State::Row = statement.next()? {
+        tasks.push((statement.read::<String>(0)?, statement.read::<String>(1)?));
+    }
+    Ok(tasks)
+}
+
+#[cfg(feature = "upyun")]
+fn record_submitted(conn: &Connection, key: &str, url: &str, task_id: &str) -> sqlite::Result<()> {
+    let mut statement = conn.prepare(
+        "INSERT OR REPLACE INTO fetches (key, url, task_id, status, submitted_at) \
+         VALUES (?, ?, ?, 'submitted', strftime('%s', 'now'))",
+    )?;
+    statement.bind(1, key)?;
+    statement.bind(2, url)?;
+    statement.bind(3, task_id)?;
+    statement.next()?;
+    Ok(())
+}
+
+/// A key already stored with the right checksum, which needs no task
+#[cfg(feature = "upyun")]
+fn record_stored(conn: &Connection, key: &str, url: &str) -> sqlite::Result<()> {
+    let mut statement = conn.prepare(
+        "INSERT OR REPLACE INTO fetches \
+         (key, url, task_id, status, description, submitted_at, finished_at) \
+         VALUES (?, ?, '', 'ok', 'already stored', strftime('%s', 'now'), strftime('%s', 'now'))",
+    )?;
+    statement.bind(1, key)?;
+    statement.bind(2, url)?;
+    statement.next()?;
+    Ok(())
+}
+
+#[cfg(feature = "upyun")]
+fn record_finished(
+    conn: &Connection,
+    task_id: &str,
+    succeeded: bool,
+    description: &str,
+) -> sqlite::Result<()> {
+    let mut statement = conn.prepare(
+        "UPDATE fetches SET status = ?, description = ?, finished_at = strftime('%s', 'now') \
+         WHERE task_id = ?",
+    )?;
+    statement.bind(1, if succeeded { "ok" } else { "failed" })?;
+    statement.bind(2, description)?;
+    statement.bind(3, task_id)?;
+    statement.next()?;
+    Ok(())
+}
+
+/// Backends and keys of the uploads the server gave up on
+fn failed_uploads(conn: &Connection) -> sqlite::Result<Vec<(String, String)>> {
+    let mut statement = conn.prepare("SELECT backend, key FROM failed_uploads")?;
+    let mut failures = vec![];
+    while let State::Row = statement.next()? {
+        failures.push((statement.read::<String>(0)?, statement.read::<String>(1)?));
+    }
+    Ok(failures)
+}
+
+fn forget_failed_upload(conn: &Connection, backend: &str, key: &str) -> sqlite::Result<()> {
+    let mut statement = conn.prepare("DELETE FROM failed_uploads WHERE backend = ? AND key = ?")?;
+    statement.bind(1, backend)?;
+    statement.bind(2, key)?;
+    statement.next()?;
+    Ok(())
+}
+
+/// Last download of each key, in seconds since the epoch
+fn last_downloads(conn: &Connection) -> sqlite::Result<HashMap<String, i64>> {
+    let mut statement = conn.prepare("SELECT key, last_downloaded_at FROM downloads")?;
+    let mut downloads = HashMap::new();
+    while let State::Row = statement.next()? {
+        downloads.insert(statement.read::<String>(0)?, statement.read::<i64>(1)?);
+    }
+    Ok(downloads)
+}
+
+/// Since when the server has counted every download and when it last flushed the counts,
+/// if it ever did
+fn counted_since(conn: &Connection) -> sqlite::Result<Option<(i64, i64)>> {
+    let mut statement = conn.prepare("SELECT min(since), min(flushed_at) FROM download_sources")?;
+    statement.next()?;
+    Ok(statement
+        .read::<Option<i64>>(0)?
+        .zip(statement.read::<Option<i64>>(1)?))
+}
+
+/// Storage class and first seen time of each key in `backend`
+fn tiers(conn: &Connection, backend: &str) -> sqlite::Result<HashMap<String, (StorageClass, i64)>> {
+    let mut statement =
+        conn.prepare("SELECT key, storage_class, first_seen_at FROM tiers WHERE backend = ?")?;
+    statement.bind(1, backend)?;
+    let mut tiers = HashMap::new();
+    while let State::Row = statement.next()? {
+        let class = statement
+            .read::<String>(1)?
+            .parse()
+            .unwrap_or(StorageClass::Standard);
+        tiers.insert(
+            statement.read::<String>(0)?,
+            (class, statement.read::<i64>(2)?),
+        );
+    }
+    Ok(tiers)
+}
+
+fn record_tier(
+    conn: &Connection,
+    backend: &str,
+    key: &str,
+    class: StorageClass,
+    first_seen: i64,
+) -> sqlite::Result<()> {
+    let mut statement = conn.prepare(
+        "INSERT OR REPLACE INTO tiers (backend, key, storage_class, first_seen_at, changed_at) \
+         VALUES (?, ?, ?, ?, strftime('%s', 'now'))",
+    )?;
+    statement.bind(1, backend)?;
+    statement.bind(2, key)?;
+    statement.bind(3, class.as_str())?;
+    statement.bind(4, first_seen)?;
+    statement.next()?;
+    Ok(())
+}
+
+/// Keys not known before, in one transaction as there may be millions on the first run
+fn record_first_seen(
+    conn: &Connection,
+    backend: &str,
+    keys: &[String],
+    now: i64,
+) -> sqlite::Result<()> {
+    conn.execute("BEGIN")?;
+    let mut statement = conn.prepare(
+        "INSERT OR IGNORE INTO tiers (backend, key, storage_class, first_seen_at) \
+         VALUES (?, ?, 'standard', ?)",
+    )?;
+    for key in keys {
+        statement.reset()?;
+        statement.bind(1, backend)?;
+        statement.bind(2, key.as_str())?;
+        statement.bind(3, now)?;
+        statement.next()?;
+    }
+    conn.execute("COMMIT")
 }
 
 impl Drop for LockGuard {
@@ -0,0 +1,141 @@
+use std::collections::HashMap;
+use std::mem;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use sqlite::Connection;
+
+/// Downloads per object key, counted in memory and added to the `downloads` table of
+/// the sync database from time to time, for `sync-crates tier` to find unpopular versions
+///
+/// Only meaningful when every download goes through `/sync`, see `key_template::through_sync`.
+pub struct DownloadStats {
+    path: PathBuf,
+    /// count and last download in seconds since the epoch, since the last flush
+    pending: Mutex<HashMap<String, (i64, i64)>>,
+}
+
+impl DownloadStats {
+    pub fn new<P: Into<PathBuf>>(path: P) -> Self {
+        Self {
+            path: path.into(),
+            pending: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn record(&self, key: &str) {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs() as i64;
+        let mut pending = self.pending.lock().unwrap();
+        let (count, last) = pending.entry(key.to_string()).or_insert((0, now));
+        *count += 1;
+        *last = now;
+    }
+
+    /// Flush every `interval` on a thread of its own, a connection cannot be shared
+    /// between threads
+    pub fn spawn_flush(&'static self, interval: Duration) {
+        thread::spawn(move || {
+            let conn = match self.open() {
+                Ok(conn) => conn,
+                Err(e) => {
+                    error!("cannot open {}: {}", self.path.display(), e);
+                    return;
+                }
+            };
+            loop {
+                thread::sleep(interval);
+                let pending = mem::take(&mut *self.pending.lock().unwrap());
+                // flushed even if empty, `sync-crates tier` takes a quiet `sync` row as
+                // downloads going uncounted
+                match flush(&conn, &pending) {
+                    Ok(()) => debug!("{} download counts flushed", pending.len()),
+                    Err(e) => {
+                        warn!("fail to flush download counts, retry later: {}", e);
+                        conn.execute("ROLLBACK").ok();
+                        let mut current = self.pending.lock().unwrap();
+                        for (key, (count, last)) in pending {
+                            let entry = current.entry(key).or_insert((0, last));
+                            entry.0 += count;
+                            entry.1 = entry.1.max(last);
+                        }
+                    }
+                }
+            }
+        });
+    }
+
+    fn open(&self) -> sqlite::Result<Connection> {
+        let mut conn = sqlite::open(&self.path)?;
+        // `sync-crates` may be writing at the same time
+        conn.set_busy_timeout(5000)?;
+        conn.execute(include_str!("bin/init.sql"))?;
+        // counting starts over if the server was gone long enough for counts to be missed
+        conn.execute(format!(
+            "INSERT OR IGNORE INTO download_sources (source, since, flushed_at) \
+             VALUES ('sync', strftime('%s', 'now'), strftime('%s', 'now')); \
+             UPDATE download_sources SET since = strftime('%s', 'now') \
+             WHERE source = 'sync' AND flushed_at < strftime('%s', 'now') - {}",
+            RESTART_AFTER
+        ))?;
+        Ok(conn)
+    }
+}
+
+/// How long the server may have been gone before downloads may have been served
+/// around it, and counting starts over
+const RESTART_AFTER: i64 = 60 * 60;
+
+/// Drop the `sync` row of the database at `path`, so `sync-crates tier` does not take
+/// counts as complete while `DL_FORMAT` bypasses `/sync`
+pub fn stop_counting<P: AsRef<Path>>(path: P) -> sqlite::Result<()> {
+    let mut conn = sqlite::open(path)?;
+    conn.set_busy_timeout(5000)?;
+    conn.execute(include_str!("bin/init.sql"))?;
+    conn.execute("DELETE FROM download_sources WHERE source = 'sync'")
+}
+
+fn flush(conn: &Connection, pending: &HashMap<String, (i64, i64)>) -> sqlite::Result<()> {
+    conn.execute("BEGIN")?;
+    let mut insert = conn.prepare(
+        "INSERT OR IGNORE INTO downloads (key, count, last_downloaded_at) VALUES (?, 0, 0)",
+    )?;
+    let mut update = conn.prepare(
+        "UPDATE downloads SET count = count + ?, \
+         last_downloaded_at = max(last_downloaded_at, ?) WHERE key = ?",
+    )?;
+    for (key, (count, last)) in pending {
+        insert.reset()?;
+        insert.bind(1, key.as_str())?;
+        insert.next()?;
+        update.reset()?;
+        update.bind(1, *count)?;
+        update.bind(2, *last)?;
+        update.bind(3, key.as_str())?;
+        update.next()?;
+    }
+    conn.execute(
+        "UPDATE download_sources SET flushed_at = strftime('%s', 'now') WHERE source = 'sync'",
+    )?;
+    conn.execute("COMMIT")
+}
+
+#[test]
+fn test_download_stats() {
+    let stats = DownloadStats::new(":memory:");
+    let conn = stats.open().unwrap();
+    stats.record("serde/1.0.0");
+    stats.record("serde/1.0.0");
+    flush(&conn, &mem::take(&mut *stats.pending.lock().unwrap())).unwrap();
+    stats.record("serde/1.0.0");
+    flush(&conn, &stats.pending.lock().unwrap()).unwrap();
+    let mut statement = conn
+        .prepare("SELECT count FROM downloads WHERE key = 'serde/1.0.0'")
+        .unwrap();
+    statement.next().unwrap();
+    assert_eq!(statement.read::<i64>(0).unwrap(), 3);
+}
@@ -1,5 +1,8 @@
 #![allow(dead_code)]
 
+use actix_web::http::StatusCode;
+use actix_web::{HttpResponse, ResponseError};
+use serde_json::json;
 use thiserror::Error;
 
 pub type Result<T> = std::result::Result<T, Error>;
@@ -18,8 +21,51 @@ pub enum Error {
     Git2(#[from] git2::Error),
     #[error(transparent)]
     EasyGit(#[from] crate::easy_git::Error),
+    #[error(transparent)]
+    Blocking(#[from] actix_web::error::BlockingError),
     #[error("missing field")]
     MissingField,
-    #[error("fail to fetch")]
-    FetchFail,
+    #[error("fail to fetch, upstream responded {0}")]
+    FetchFail(reqwest::StatusCode),
+    #[error("crate not found")]
+    NotFound,
+    #[error("too many active downloads")]
+    Overloaded,
+}
+
+impl Error {
+    /// Stable identifier of the failure, for monitoring
+    pub fn kind(&self) -> &'static str {
+        match self.status_code() {
+            StatusCode::NOT_FOUND => "not_found",
+            StatusCode::BAD_GATEWAY => "upstream_failure",
+            StatusCode::SERVICE_UNAVAILABLE => "overloaded",
+            StatusCode::GATEWAY_TIMEOUT => "upstream_timeout",
+            _ => "internal",
+        }
+    }
+}
+
+/// Errors are answered in the registry API format cargo prints to users
+/// ```json
+/// {"errors": [{"detail": "crate not found", "kind": "not_found"}]}
+/// ```
+impl ResponseError for Error {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            Error::NotFound => StatusCode::NOT_FOUND,
+            Error::Overloaded => StatusCode::SERVICE_UNAVAILABLE,
+            Error::Reqwest(e) if e.is_timeout() => StatusCode::GATEWAY_TIMEOUT,
+            Error::Reqwest(_) | Error::Header(_) | Error::MissingField | Error::FetchFail(_) => {
+                StatusCode::BAD_GATEWAY
+            }
+            _ => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        HttpResponse::build(self.status_code()).json(&json!({
+            "errors": [{ "detail": self.to_string(), "kind": self.kind() }]
+        }))
+    }
 }
@@ -0,0 +1,30 @@
+use std::env;
+
+/// Note in the sync database at `SYNC_DB` that `key` could not be uploaded to `backend`,
+/// for `sync-crates retry` to copy it over from another backend
+pub async fn record(backend: &str, key: &str) {
+    let path = match env::var("SYNC_DB") {
+        Ok(path) => path,
+        Err(_) => return,
+    };
+    let (backend, key) = (backend.to_string(), key.to_string());
+    let recorded = tokio::task::spawn_blocking(move || -> sqlite::Result<()> {
+        let mut conn = sqlite::open(&path)?;
+        conn.set_busy_timeout(5000)?;
+        conn.execute(include_str!("bin/init.sql"))?;
+        let mut statement = conn.prepare(
+            "INSERT OR REPLACE INTO failed_uploads (backend, key, failed_at) \
+             VALUES (?, ?, strftime('%s', 'now'))",
+        )?;
+        statement.bind(1, backend.as_str())?;
+        statement.bind(2, key.as_str())?;
+        statement.next()?;
+        Ok(())
+    })
+    .await;
+    match recorded {
+        Ok(Ok(())) => (),
+        Ok(Err(e)) => error!("fail to record failed upload: {}", e),
+        Err(e) => error!("fail to record failed upload: {}", e),
+    }
+}
@@ -1,17 +1,24 @@
-use bytes::{Bytes, BytesMut};
+use bytes::Bytes;
 use serde::Deserialize;
+use sha2::{Digest, Sha256};
 use std::env;
+use std::fs;
+use std::io;
 use std::sync::Arc;
-use tokio::sync::{mpsc, watch, RwLock};
-use tokio_stream::StreamExt;
-use reqwest::StatusCode;
+use std::time::Duration;
+use tokio::sync::{mpsc, watch, OwnedSemaphorePermit, RwLock, Semaphore};
+use tokio_stream::wrappers::UnboundedReceiverStream;
+use tokio_stream::{Stream, StreamExt};
+use reqwest::{header, Certificate, Proxy, Response, StatusCode};
 
 use crate::error::Error;
-use crate::ACTIVE_DOWNLOADS;
-#[cfg(feature = "obs")]
-use crate::simple_obs::{AutoRefreshingProvider, Bucket, IamProvider, ProvideObsCredentials, Ssl};
-#[cfg(feature = "upyun")]
-use crate::upyun::{Operator, Upyun};
+use crate::index::IndexEntry;
+use crate::{ACTIVE_DOWNLOADS, DL_FORMAT, KEY_TEMPLATE, MAX_ACTIVE_DOWNLOADS, NEGATIVE_CACHE};
+pub use crates_io_cn::env_or;
+use crates_io_cn::index_entry::upstream_url;
+use crates_io_cn::key_template::{dl_url, through_sync};
+use crates_io_cn::retry::backoff;
+use crates_io_cn::storage::{self, ObjectMeta, Replica, StorageClass, StorageError};
 
 #[derive(Clone, Debug, Deserialize, Hash, Eq, PartialEq)]
 pub struct CrateReq {
@@ -21,124 +28,484 @@ pub struct CrateReq {
     version: String,
 }
 
-lazy_static! {
-    static ref CLIENT: reqwest::Client = reqwest::Client::new();
+impl CrateReq {
+    pub fn new<N: Into<String>, V: Into<String>>(name: N, version: V) -> Self {
+        Self {
+            name: name.into(),
+            version: version.into(),
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn version(&self) -> &str {
+        &self.version
+    }
 }
-#[cfg(feature = "upyun")]
-lazy_static! {
-    static ref UPYUN_NAME: &'static str =
-        Box::leak(env::var("UPYUN_NAME").unwrap().into_boxed_str());
-    static ref UPYUN_TOKEN: &'static str =
-        Box::leak(env::var("UPYUN_TOKEN").unwrap().into_boxed_str());
-    static ref UPYUN_BUCKET: &'static str =
-        Box::leak(env::var("UPYUN_BUCKET").unwrap().into_boxed_str());
-    static ref UPYUN: Upyun = Upyun::new(Operator::new(&UPYUN_NAME, &UPYUN_TOKEN));
+
+impl From<&IndexEntry> for CrateReq {
+    fn from(entry: &IndexEntry) -> Self {
+        CrateReq::new(&entry.name, &entry.vers)
+    }
 }
-#[cfg(feature = "obs")]
+
 lazy_static! {
-    static ref OBS_BUCKET_NAME: &'static str =
-        Box::leak(env::var("OBS_BUCKET_NAME").unwrap().into_boxed_str());
-    static ref OBS_ENDPOINT: &'static str =
-        Box::leak(env::var("OBS_ENDPOINT").unwrap().into_boxed_str());
-    static ref OBS_CREDENTIALS: AutoRefreshingProvider<IamProvider> =
-        AutoRefreshingProvider::new(IamProvider::new());
-    static ref OBS_BUCKET: Bucket = Bucket::new(&OBS_BUCKET_NAME, &OBS_ENDPOINT, Ssl::Yes);
+    static ref CLIENT: reqwest::Client =
+        upstream_client().expect("invalid upstream client configuration");
+    static ref UPSTREAM_READ_TIMEOUT: Duration =
+        Duration::from_secs(env_or("UPSTREAM_READ_TIMEOUT", 30));
+    static ref UPSTREAM_RESUME_ATTEMPTS: usize = env_or("UPSTREAM_RESUME_ATTEMPTS", 3);
+    static ref UPLOAD_ATTEMPTS: u32 = env_or("UPLOAD_ATTEMPTS", 10);
+    /// Permits for concurrent downloads from static.crates.io, `UPSTREAM_MAX_CONCURRENT`,
+    /// unlimited if 0
+    static ref UPSTREAM_DOWNLOADS: Option<Arc<Semaphore>> =
+        match env_or("UPSTREAM_MAX_CONCURRENT", 0) {
+            0 => None,
+            permits => Some(Arc::new(Semaphore::new(permits))),
+        };
+    /// Seconds a request waits for one of those permits before it is turned away
+    static ref UPSTREAM_QUEUE_TIMEOUT: Duration =
+        Duration::from_secs(env_or("UPSTREAM_QUEUE_TIMEOUT", 30));
+    /// Lifetime of signed download urls, `0` disables redirecting to storage
+    pub static ref SIGNED_URL_TTL: Duration = Duration::from_secs(env_or("SIGNED_URL_TTL", 0));
+    /// Days an archived crate stays readable once requested
+    static ref RESTORE_DAYS: u32 = env_or("STORAGE_RESTORE_DAYS", 7);
+    /// Every backend crates are uploaded to
+    pub static ref STORAGES: Vec<Replica> = storage::backends_from_env()
+        .iter()
+        .map(|name| Replica::new(storage::from_env(name).expect("invalid storage backend")))
+        .collect();
+}
+
+/// A time-limited url for an already stored crate from the first backend able to sign one,
+/// `None` if no backend has it readable
+///
+/// An archived crate is restored, and served from elsewhere until that completes. An object
+/// whose SHA-256 metadata is not `cksum` from the index is never redirected to.
+pub async fn signed_url(key: &str, cksum: &str) -> Option<String> {
+    for replica in STORAGES.iter() {
+        let signed = match replica.storage.signed_url(key, *SIGNED_URL_TTL).await {
+            Ok(Some(url)) => match replica.storage.head(key).await {
+                Ok(Some(meta)) if mismatches(&meta, cksum) => {
+                    warn!("{} in {} does not match the index", key, replica.name());
+                    None
+                }
+                Ok(Some(meta)) if meta.archived => {
+                    debug!("{} is archived in {}, restore it", key, replica.name());
+                    if let Err(e) = replica.storage.restore(key, *RESTORE_DAYS).await {
+                        warn!("fail to restore {} on {}: {}", key, replica.name(), e);
+                    }
+                    None
+                }
+                Ok(Some(_)) => Some(url),
+                Ok(None) => None,
+                Err(e) => {
+                    warn!("fail to check {} on {}: {}", key, replica.name(), e);
+                    None
+                }
+            },
+            Ok(None) => None,
+            Err(e) => {
+                warn!("fail to sign {} on {}: {}", key, replica.name(), e);
+                None
+            }
+        };
+        if signed.is_some() {
+            return signed;
+        }
+    }
+    None
+}
+
+/// Whether `meta` records a SHA-256 other than `cksum`, objects without one are trusted
+fn mismatches(meta: &ObjectMeta, cksum: &str) -> bool {
+    matches!(meta.sha256.as_deref(), Some(sha256) if sha256 != cksum)
+}
+
+/// Build the client used to fetch crates from static.crates.io
+///
+/// - `UPSTREAM_CONNECT_TIMEOUT`: connect timeout in seconds, default 10
+/// - `UPSTREAM_PROXY`: proxy for all upstream traffic, `http://`, `https://` or `socks5://`
+/// - `UPSTREAM_CA_CERTS`: comma separated PEM files added to the trusted roots
+fn upstream_client() -> Result<reqwest::Client, Error> {
+    let mut builder = reqwest::Client::builder()
+        .connect_timeout(Duration::from_secs(env_or("UPSTREAM_CONNECT_TIMEOUT", 10)));
+    if let Ok(proxy) = env::var("UPSTREAM_PROXY") {
+        builder = builder.proxy(Proxy::all(&proxy)?);
+    }
+    if let Ok(certs) = env::var("UPSTREAM_CA_CERTS") {
+        for path in certs.split(',').map(str::trim).filter(|p| !p.is_empty()) {
+            builder = builder.add_root_certificate(Certificate::from_pem(&fs::read(path)?)?);
+        }
+    }
+    Ok(builder.build()?)
+}
+
+/// A slot for one more download from static.crates.io, `None` if unlimited,
+/// `Error::Overloaded` if none frees up within `timeout`
+async fn upstream_permit(timeout: Option<Duration>) -> Result<Option<OwnedSemaphorePermit>, Error> {
+    let downloads = match *UPSTREAM_DOWNLOADS {
+        Some(ref downloads) => downloads.clone(),
+        None => return Ok(None),
+    };
+    let acquire = downloads.acquire_owned();
+    let permit = match timeout {
+        Some(timeout) => tokio::time::timeout(timeout, acquire)
+            .await
+            .map_err(|_| Error::Overloaded)?,
+        None => acquire.await,
+    };
+    Ok(Some(permit.expect("never closed")))
+}
+
+/// Continue an interrupted download from `offset`
+async fn resume(uri: &str, offset: usize) -> Result<Response, Error> {
+    let resp = CLIENT
+        .get(uri)
+        .header(header::RANGE, format!("bytes={}-", offset))
+        .send()
+        .await?;
+    let expected = format!("bytes {}-", offset);
+    let range_matched = matches!(
+        resp.headers().get(header::CONTENT_RANGE).map(|v| v.to_str()),
+        Some(Ok(range)) if range.starts_with(&expected)
+    );
+    if resp.status() != StatusCode::PARTIAL_CONTENT || !range_matched {
+        return Err(Error::FetchFail(resp.status()));
+    }
+    Ok(resp)
+}
+
+/// Download state shared with every reader of a crate
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Progress {
+    /// bytes buffered so far
+    Downloading(usize),
+    Complete,
+    Failed,
 }
 
 #[derive(Clone, Debug)]
 pub struct Crate {
-    name: String,
-    version: String,
-    content_type: String,
-    pub content_length: usize,
-    pub buffer: Arc<RwLock<BytesMut>>,
-    pub notify: watch::Receiver<usize>,
-    ptr: usize,
+    /// object key in storage
+    pub key: String,
+    pub content_type: String,
+    pub content_length: Option<usize>,
+    /// SHA-256 hex from the index
+    cksum: String,
+    /// where cargo downloads the crate from, purged from the CDN after a re-upload unless
+    /// it goes through `/sync`
+    dl_url: String,
+    /// chunks as received from upstream, shared without copying by every reader
+    pub buffer: Arc<RwLock<Vec<Bytes>>>,
+    pub notify: watch::Receiver<Progress>,
 }
 
 impl Crate {
-    pub async fn create(krate_req: CrateReq) -> Result<Arc<Self>, Error> {
+    /// Download `entry` for a request, turned away with `Error::Overloaded` beyond
+    /// `MAX_ACTIVE_DOWNLOADS` or after waiting `UPSTREAM_QUEUE_TIMEOUT` for upstream
+    pub async fn create(entry: IndexEntry) -> Result<Arc<Self>, Error> {
+        Self::start(entry, true).await
+    }
+
+    /// Download `entry` to mirror it, however long that has to wait
+    pub async fn prefetch(entry: IndexEntry) -> Result<Arc<Self>, Error> {
+        Self::start(entry, false).await
+    }
+
+    async fn start(entry: IndexEntry, requested: bool) -> Result<Arc<Self>, Error> {
+        let krate_req = CrateReq::from(&entry);
         if let Some(krate) = ACTIVE_DOWNLOADS.read().await.get(&krate_req) {
             return Ok(krate.clone());
         }
+        if NEGATIVE_CACHE.contains(&krate_req) {
+            return Err(Error::NotFound);
+        }
+        // held until the download ends, waited for before blocking other requests
+        let queue_timeout = if requested {
+            Some(*UPSTREAM_QUEUE_TIMEOUT)
+        } else {
+            None
+        };
+        let permit = upstream_permit(queue_timeout).await?;
         let mut guard = ACTIVE_DOWNLOADS.write().await;
-        let CrateReq { name, version } = krate_req.clone();
-        let uri = format!(
-            "https://static.crates.io/crates/{name}/{name}-{version}.crate",
-            name = name,
-            version = version
-        );
-        let key = format!("{}/{}", name, version);
+        if let Some(krate) = guard.get(&krate_req) {
+            return Ok(krate.clone());
+        }
+        if requested && guard.len() >= *MAX_ACTIVE_DOWNLOADS {
+            return Err(Error::Overloaded);
+        }
+        let uri = upstream_url(krate_req.name(), krate_req.version());
         let krate_req_key = krate_req.clone();
         let resp = CLIENT.get(&uri).send().await?;
-        if resp.status() != StatusCode::OK {
-            return Err(Error::FetchFail);
+        match resp.status() {
+            StatusCode::OK => (),
+            StatusCode::NOT_FOUND | StatusCode::FORBIDDEN => {
+                NEGATIVE_CACHE.insert(krate_req);
+                return Err(Error::NotFound);
+            }
+            status => return Err(Error::FetchFail(status)),
         }
-        let content_length = resp.content_length().ok_or(Error::MissingField)? as usize;
-        let (tx, rx) = watch::channel(0);
-        let krate = Self {
-            name,
-            version,
-            content_type: resp
-                .headers()
-                .get("content-type")
-                .ok_or(Error::MissingField)?
-                .to_str()?
-                .to_string(),
+        let content_length = resp.content_length().map(|l| l as usize);
+        let content_type = match resp.headers().get(header::CONTENT_TYPE) {
+            Some(value) => value.to_str()?.to_string(),
+            None => "application/octet-stream".to_string(),
+        };
+        let (tx, rx) = watch::channel(Progress::Downloading(0));
+        let krate = Arc::new(Self {
+            key: KEY_TEMPLATE.render(&entry),
+            content_type,
             content_length,
-            buffer: Arc::new(RwLock::new(BytesMut::with_capacity(
-                content_length as usize,
-            ))),
+            cksum: entry.cksum.clone(),
+            dl_url: dl_url(*DL_FORMAT, &entry),
+            buffer: Arc::new(RwLock::new(Vec::new())),
             notify: rx,
-            ptr: 0,
-        };
+        });
         let write_buffer = krate.buffer.clone();
+        let cksum = entry.cksum;
         tokio::spawn(async move {
+            let _permit = permit;
             let mut stream = resp.bytes_stream();
-            while let Some(chunk) = stream.next().await {
-                match chunk {
-                    Ok(data) => {
-                        let mut buffer = write_buffer.write().await;
-                        trace!("recv {}", data.len());
-                        buffer.extend_from_slice(&data[..]);
-                        tx.send(data.len()).unwrap();
-                    }
-                    Err(e) => {
-                        error!("{}", e);
-                        break;
+            let mut hasher = Sha256::new();
+            let mut received = 0;
+            let mut attempts = 0;
+            let mut finished = false;
+            'download: loop {
+                let mut reason =
+                    match tokio::time::timeout(*UPSTREAM_READ_TIMEOUT, stream.next()).await {
+                        Ok(Some(Ok(data))) => {
+                            trace!("recv {}", data.len());
+                            received += data.len();
+                            hasher.update(&data);
+                            write_buffer.write().await.push(data);
+                            tx.send(Progress::Downloading(received)).ok();
+                            continue;
+                        }
+                        Ok(None) => {
+                            finished = true;
+                            break;
+                        }
+                        Ok(Some(Err(e))) => e.to_string(),
+                        Err(_) => "read timeout".to_string(),
+                    };
+                while attempts < *UPSTREAM_RESUME_ATTEMPTS {
+                    attempts += 1;
+                    warn!(
+                        "{:?} interrupted at {} bytes: {}, resume attempt {}",
+                        krate_req_key, received, reason, attempts
+                    );
+                    match resume(&uri, received).await {
+                        Ok(resp) => {
+                            stream = resp.bytes_stream();
+                            continue 'download;
+                        }
+                        Err(e) => reason = e.to_string(),
                     }
-                };
-            }
-            let buffer = write_buffer.read().await.clone().freeze();
-            debug!("{:?} download complete", krate_req_key);
-            let mut counter: i32 = 10;
-            while counter > 0 {
-                #[cfg(feature = "obs")]
-                let result = match OBS_CREDENTIALS.credentials().await {
-                    Ok(credentials) => OBS_BUCKET
-                        .put(&key, buffer.clone(), &credentials)
-                        .await
-                        .err(),
-                    Err(e) => Some(e),
-                };
-                #[cfg(feature = "upyun")]
-                let result = UPYUN
-                    .put_file(*UPYUN_BUCKET, &key, buffer.clone())
-                    .await
-                    .err();
-                if let Some(e) = result {
-                    error!("retry attempt {}:{}", 10 - counter, e);
-                    counter -= 1;
-                    continue;
                 }
-                ACTIVE_DOWNLOADS.write().await.remove(&krate_req_key);
-                debug!("remove {:?} from active download", krate_req_key);
+                error!("{:?} download failed: {}", krate_req_key, reason);
                 break;
             }
+            if !finished || matches!(content_length, Some(l) if l != received) {
+                error!(
+                    "{:?} incomplete, received {} of {:?} bytes",
+                    krate_req_key, received, content_length
+                );
+                tx.send(Progress::Failed).ok();
+                return;
+            }
+            let checksum = format!("{:x}", hasher.finalize());
+            if checksum != cksum {
+                error!(
+                    "{:?} corrupted, sha256 {} does not match index {}",
+                    krate_req_key, checksum, cksum
+                );
+                tx.send(Progress::Failed).ok();
+                return;
+            }
+            tx.send(Progress::Complete).ok();
+            debug!("{:?} download complete", krate_req_key);
+        });
+        // uploads while downloading, and go on with their own reference once it is done
+        let upload = krate.clone();
+        let krate_req_key = krate_req.clone();
+        tokio::spawn(async move { upload.upload(&krate_req_key).await });
+        // leaves active downloads when the download ends, whatever the uploads are doing
+        let download = krate.clone();
+        let krate_req_key = krate_req.clone();
+        tokio::spawn(async move {
+            download.completed().await;
+            ACTIVE_DOWNLOADS.write().await.remove(&krate_req_key);
+            debug!("remove {:?} from active download", krate_req_key);
         });
-        guard.insert(krate_req.clone(), Arc::new(krate));
+        guard.insert(krate_req.clone(), krate.clone());
         debug!("insert {:?} into active download", krate_req);
-        Ok(guard.get(&krate_req).unwrap().clone())
+        Ok(krate)
+    }
+
+    /// Stream the crate to every backend at once, each retried on its own
+    async fn upload(&self, krate_req: &CrateReq) {
+        let uploads = STORAGES
+            .iter()
+            .map(|replica| self.upload_to(replica, &self.key, krate_req));
+        futures::future::join_all(uploads).await;
+    }
+
+    async fn upload_to(&self, replica: &Replica, key: &str, krate_req: &CrateReq) {
+        let content_addressed = KEY_TEMPLATE.is_content_addressed();
+        // a blob must never be written before its checksum is verified
+        if content_addressed && !self.completed().await {
+            debug!("{:?} download failed, abandon upload", krate_req);
+            return;
+        }
+        // one look at what is stored decides whether to upload at all, and whether the CDN
+        // may still be serving the copy being replaced
+        let replacing = match replica.storage.head(key).await {
+            // a blob with other metadata is broken, `signed_url` never serves it
+            Ok(Some(meta)) if content_addressed && !mismatches(&meta, &self.cksum) => {
+                debug!(
+                    "{:?} already stored in {} as {}",
+                    krate_req,
+                    replica.name(),
+                    key
+                );
+                replica.record(true);
+                return;
+            }
+            // archived by `sync-crates tier`, maybe being restored for `signed_url`,
+            // overwriting it would undo both
+            Ok(Some(meta)) if meta.storage_class == StorageClass::Cold => {
+                debug!(
+                    "{:?} archived in {}, keep it as is",
+                    krate_req,
+                    replica.name()
+                );
+                replica.record(true);
+                return;
+            }
+            Ok(meta) => meta.is_some(),
+            Err(e) => {
+                warn!("fail to check {} on {}: {}", key, replica.name(), e);
+                false
+            }
+        };
+        let mut attempt = 1;
+        loop {
+            let result = replica
+                .put(
+                    key,
+                    Box::pin(self.stream()),
+                    self.content_length,
+                    &self.cksum,
+                )
+                .await;
+            let result = match result {
+                Ok(()) => self.verify(replica, key).await,
+                Err(e) => Err(e),
+            };
+            match result {
+                Ok(()) => {
+                    debug!(
+                        "{:?} uploaded to {} and verified",
+                        krate_req,
+                        replica.name()
+                    );
+                    replica.record(true);
+                    // a failed attempt may have left a broken copy too
+                    if replacing || attempt > 1 {
+                        self.purge(replica).await;
+                    }
+                    break;
+                }
+                Err(_) if *self.notify.borrow() == Progress::Failed => {
+                    debug!("{:?} download failed, abandon upload", krate_req);
+                    break;
+                }
+                Err(e) if e.is_retryable() && attempt < *UPLOAD_ATTEMPTS => {
+                    let delay = backoff(attempt);
+                    warn!(
+                        "{:?} upload to {} attempt {} failed: {}, retry in {:?}",
+                        krate_req,
+                        replica.name(),
+                        attempt,
+                        e,
+                        delay
+                    );
+                    attempt += 1;
+                    tokio::time::sleep(delay).await;
+                }
+                Err(e) => {
+                    error!("{:?} upload to {} failed: {}", krate_req, replica.name(), e);
+                    replica.record(false);
+                    #[cfg(feature = "sync")]
+                    crate::failed_uploads::record(replica.name(), key).await;
+                    break;
+                }
+            }
+        }
+    }
+
+    async fn purge(&self, replica: &Replica) {
+        // cargo is redirected from `/sync` to the CDN of the replica, not served `dl_url`
+        let url = if through_sync(*DL_FORMAT) {
+            match replica.storage.cdn_url(&self.key) {
+                Some(url) => url,
+                None => return,
+            }
+        } else {
+            self.dl_url.clone()
+        };
+        match replica.storage.purge(std::slice::from_ref(&url)).await {
+            Ok(()) => info!("{} purged from the CDN of {}", url, replica.name()),
+            Err(StorageError::Unsupported(_)) => (),
+            Err(e) => warn!("fail to purge {} on {}: {}", url, replica.name(), e),
+        }
+    }
+
+    /// Check that what `replica` stored under `key` has the size and SHA-256 of the crate
+    async fn verify(&self, replica: &Replica, key: &str) -> storage::Result<()> {
+        let size: usize = self.buffer.read().await.iter().map(Bytes::len).sum();
+        let stored = replica.storage.head(key).await?;
+        match stored {
+            Some(ref meta)
+                if meta.size == size as u64 && meta.sha256.as_deref() == Some(&*self.cksum) =>
+            {
+                Ok(())
+            }
+            _ => Err(StorageError::Checksum {
+                key: key.to_string(),
+                expected: format!("{} bytes with sha256 {}", size, self.cksum),
+                actual: match stored {
+                    Some(meta) => format!("{} bytes with sha256 {:?}", meta.size, meta.sha256),
+                    None => "nothing".to_string(),
+                },
+            }),
+        }
+    }
+
+    /// Wait for the download to end, `true` if it completed and passed the checksum
+    async fn completed(&self) -> bool {
+        let mut notify = self.notify.clone();
+        loop {
+            match *notify.borrow() {
+                Progress::Complete => return true,
+                Progress::Failed => return false,
+                Progress::Downloading(_) => (),
+            }
+            if notify.changed().await.is_err() {
+                return *notify.borrow() == Progress::Complete;
+            }
+        }
+    }
+
+    /// Every chunk of the crate from the start, as it is downloaded
+    pub fn stream(&self) -> impl Stream<Item = io::Result<Bytes>> + Send + Sync + 'static {
+        let (tx, rx) = mpsc::unbounded_channel();
+        self.tee(tx);
+        // the download stopped short of the full crate
+        UnboundedReceiverStream::new(rx)
+            .map(|data| data.map_err(|_| io::Error::from(io::ErrorKind::UnexpectedEof)))
     }
 
     pub fn tee(&self, tx: mpsc::UnboundedSender<Result<Bytes, ()>>) {
@@ -147,26 +514,32 @@ impl Crate {
         tokio::spawn(async move {
             let mut ptr = 0;
             loop {
-                let data = {
+                // read progress before the buffer, so `Complete` guarantees we see every chunk
+                let progress = *notify.borrow();
+                let chunks = {
                     let buffer = krate.buffer.read().await;
-                    let data = Bytes::copy_from_slice(&buffer[ptr..]);
-                    ptr += data.len();
-                    data
+                    let chunks = buffer[ptr..].to_vec();
+                    ptr = buffer.len();
+                    chunks
                 };
-                match tx.send(Ok(data)) {
-                    Ok(_) => (),
-                    Err(e) => {
-                        error!("{}", e);
-                        break;
+                for data in chunks {
+                    if let Err(e) = tx.send(Ok(data)) {
+                        debug!("{}", e);
+                        return;
                     }
                 }
-                info!("{}/{}", ptr, krate.content_length);
-                if ptr == krate.content_length {
-                    break;
+                trace!("{}/{:?}", ptr, krate.content_length);
+                match progress {
+                    Progress::Complete => break,
+                    Progress::Failed => {
+                        tx.send(Err(())).ok();
+                        break;
+                    }
+                    Progress::Downloading(_) => (),
                 }
                 if let Err(e) = notify.changed().await {
                     debug!("{}", e);
-                    tx.closed().await;
+                    tx.send(Err(())).ok();
                     break;
                 }
             }
@@ -1,4 +1,5 @@
 use std::fs::{File, OpenOptions};
+use std::io::{self, BufReader};
 use std::path::Path;
 use std::sync::{Arc, RwLock};
 
@@ -8,6 +9,7 @@ use serde::{Deserialize, Serialize};
 use crate::error::Error;
 use crate::helper::CrateReq;
 use crate::easy_git::EasyGit;
+pub use crates_io_cn::index_entry::{index_path, read_entries, IndexEntry};
 
 use std::env;
 use std::io::Write;
@@ -75,14 +77,14 @@ impl GitIndex {
         })
     }
 
-    pub fn update(&self) -> Result<Vec<CrateReq>, Error> {
+    pub fn update(&self) -> Result<Vec<IndexEntry>, Error> {
         self.repo.fetch_origin()?;
         let crates = self.diff("HEAD~1", "origin/HEAD")?;
         self.repo.rebase_master()?;
         Ok(crates)
     }
 
-    fn diff<A, B>(&self, a: A, b: B) -> Result<Vec<CrateReq>, Error>
+    fn diff<A, B>(&self, a: A, b: B) -> Result<Vec<IndexEntry>, Error>
     where
         A: AsRef<str>,
         B: AsRef<str>,
@@ -112,7 +114,7 @@ impl GitIndex {
             true
         };
         diff.foreach(&mut file_cb, None, None, Some(&mut line_cb))?;
-        let crates: Vec<CrateReq> = lines
+        let crates: Vec<IndexEntry> = lines
             .into_inner()
             .unwrap()
             .iter()
@@ -123,6 +125,68 @@ impl GitIndex {
     }
 }
 
+/// crates.io crate names: ascii alphanumeric, `-` or `_`, start with a letter, at most 64 chars
+pub fn is_valid_name(name: &str) -> bool {
+    name.len() <= 64
+        && name.starts_with(|c: char| c.is_ascii_alphabetic())
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+/// semver characters only, rejecting anything that could escape a path
+pub fn is_valid_version(version: &str) -> bool {
+    !version.is_empty()
+        && version.len() <= 128
+        && version
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '.' || c == '-' || c == '+')
+}
+
+/// Look up `krate` in the index checked out at `root`
+///
+/// Names are matched case-insensitively and with `-` and `_` treated alike,
+/// as crates.io does. The returned entry carries the canonical name.
+pub fn find<P: AsRef<Path>>(root: P, krate: &CrateReq) -> Result<Option<IndexEntry>, Error> {
+    if !is_valid_name(krate.name()) || !is_valid_version(krate.version()) {
+        return Ok(None);
+    }
+    let name = krate.name().to_lowercase();
+    let mut candidates = vec![name.clone()];
+    for candidate in [name.replace('_', "-"), name.replace('-', "_")].iter() {
+        if !candidates.contains(candidate) {
+            candidates.push(candidate.clone());
+        }
+    }
+    for candidate in candidates {
+        let file = match File::open(root.as_ref().join(index_path(&candidate))) {
+            Ok(file) => file,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(e.into()),
+        };
+        let (entries, skipped) = read_entries(BufReader::new(file))?;
+        if skipped > 0 {
+            warn!(
+                "{} invalid lines in the index file of {}",
+                skipped, candidate
+            );
+        }
+        return Ok(entries
+            .into_iter()
+            .find(|entry| entry.vers == krate.version()));
+    }
+    Ok(None)
+}
+
+#[test]
+fn test_is_valid() {
+    assert!(is_valid_name("serde_json"));
+    assert!(!is_valid_name("../serde"));
+    assert!(!is_valid_name("1serde"));
+    assert!(is_valid_version("1.0.0-beta.3+build"));
+    assert!(!is_valid_version("1.0/../.."));
+}
+
 #[test]
 fn test() {
     log4rs::init_file("config/log4rs.yml", Default::default()).unwrap();
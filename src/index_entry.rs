@@ -0,0 +1,96 @@
+//! Entries of crates.io-index
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader};
+use std::path::Path;
+
+use serde::Deserialize;
+
+/// A version line of a crate file in crates-io-index
+#[derive(Debug, Clone, Eq, PartialEq, Deserialize)]
+pub struct IndexEntry {
+    pub name: String,
+    pub vers: String,
+    pub cksum: String,
+    #[serde(default)]
+    pub yanked: bool,
+}
+
+/// Directory of a crate in the index, the `{prefix}` of cargo's `dl` markers
+///
+/// - `1`, `2` for one and two chars
+/// - `3/{n}` for three chars
+/// - `{na}/{me}` for the rest
+pub fn prefix(name: &str) -> String {
+    match name.len() {
+        1 => "1".to_string(),
+        2 => "2".to_string(),
+        3 => format!("3/{}", &name[..1]),
+        _ => format!("{}/{}", &name[..2], &name[2..4]),
+    }
+}
+
+/// Path of a crate file relative to the index root, the same layout cargo uses
+pub fn index_path(name: &str) -> String {
+    let name = name.to_lowercase();
+    format!("{}/{}", prefix(&name), name)
+}
+
+/// Where static.crates.io serves the tarball of `name` at `version`
+pub fn upstream_url(name: &str, version: &str) -> String {
+    format!(
+        "https://static.crates.io/crates/{name}/{name}-{version}.crate",
+        name = name,
+        version = version
+    )
+}
+
+/// Version lines of a crate file, and how many lines were not valid entries
+pub fn read_entries<R: BufRead>(reader: R) -> io::Result<(Vec<IndexEntry>, usize)> {
+    let (mut entries, mut skipped) = (vec![], 0);
+    for line in reader.lines() {
+        match serde_json::from_str(&line?) {
+            Ok(entry) => entries.push(entry),
+            Err(_) => skipped += 1,
+        }
+    }
+    Ok((entries, skipped))
+}
+
+/// Every version of crate `name` in the index checked out at `root`, and lines skipped
+pub fn crate_entries(root: &Path, name: &str) -> io::Result<(Vec<IndexEntry>, usize)> {
+    read_entries(BufReader::new(File::open(root.join(index_path(name)))?))
+}
+
+/// Every version in the index checked out at `root`, and lines skipped
+pub fn all_entries(root: &Path) -> io::Result<(Vec<IndexEntry>, usize)> {
+    let (mut entries, mut skipped) = (vec![], 0);
+    let mut dirs = vec![root.to_path_buf()];
+    while let Some(dir) = dirs.pop() {
+        for file in fs::read_dir(dir)? {
+            let file = file?;
+            let name = file.file_name();
+            if name.to_string_lossy().starts_with('.') || name == "config.json" {
+                continue;
+            }
+            if file.file_type()?.is_dir() {
+                dirs.push(file.path());
+                continue;
+            }
+            let (found, invalid) = read_entries(BufReader::new(File::open(file.path())?))?;
+            entries.extend(found);
+            skipped += invalid;
+        }
+    }
+    Ok((entries, skipped))
+}
+
+#[test]
+fn test_index_path() {
+    assert_eq!(index_path("a"), "1/a");
+    assert_eq!(index_path("ab"), "2/ab");
+    assert_eq!(index_path("abc"), "3/a/abc");
+    assert_eq!(index_path("Serde"), "se/rd/serde");
+    let lines = "{\"name\":\"a\",\"vers\":\"0.1.0\",\"cksum\":\"ab\"}\nnot json\n";
+    let (entries, skipped) = read_entries(lines.as_bytes()).unwrap();
+    assert_eq!((entries.len(), skipped), (1, 1));
+}
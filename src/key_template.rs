@@ -0,0 +1,194 @@
+use thiserror::Error;
+
+use crate::index_entry::{prefix, IndexEntry};
+
+const MARKERS: [&str; 5] = [
+    "{crate}",
+    "{version}",
+    "{prefix}",
+    "{lowerprefix}",
+    "{sha256-checksum}",
+];
+
+/// Route of the mirror serving crates on demand, markers as in `dl`
+pub const SYNC_PATH: &str = "/sync/{crate}/{version}";
+
+#[derive(Debug, Error)]
+#[error("invalid key template: {0}")]
+pub struct TemplateError(String);
+
+/// Layout of object keys in storage, with the markers cargo supports in `dl`
+///
+/// `{crate}`, `{version}`, `{prefix}`, `{lowerprefix}` and `{sha256-checksum}`,
+/// e.g. `{lowerprefix}/{crate}/{crate}-{version}.crate`
+#[derive(Clone, Debug)]
+pub struct KeyTemplate(String);
+
+impl KeyTemplate {
+    /// Rejects unknown markers and templates that would not give every version its own key
+    pub fn new<T: Into<String>>(template: T) -> Result<Self, TemplateError> {
+        let template = template.into();
+        let mut rest = template.as_str();
+        while let Some(start) = rest.find('{') {
+            let end = rest[start..]
+                .find('}')
+                .ok_or_else(|| TemplateError(format!("unclosed marker in {}", template)))?;
+            let marker = &rest[start..=start + end];
+            if !MARKERS.contains(&marker) {
+                return Err(TemplateError(format!("unknown marker {}", marker)));
+            }
+            rest = &rest[start + end + 1..];
+        }
+        let unique = (template.contains("{crate}") && template.contains("{version}"))
+            || template.contains("{sha256-checksum}");
+        if !unique || template.starts_with('/') {
+            return Err(TemplateError(format!(
+                "{} must be relative and contain {{crate}} and {{version}} or {{sha256-checksum}}",
+                template
+            )));
+        }
+        Ok(KeyTemplate(template))
+    }
+
+    /// Whether keys depend on the content only, so equal tarballs share one object
+    pub fn is_content_addressed(&self) -> bool {
+        self.0.contains("{sha256-checksum}")
+            && !["{crate}", "{version}", "{prefix}", "{lowerprefix}"]
+                .iter()
+                .any(|marker| self.0.contains(marker))
+    }
+
+    pub fn render(&self, entry: &IndexEntry) -> String {
+        render(&self.0, entry)
+    }
+
+    /// Check that cargo, following `dl` from the index config, requests the keys of this template
+    ///
+    /// This protects deployments where a CDN serves the bucket and cargo downloads straight
+    /// from it, only falling back to this mirror for missing objects: the path of `dl` must
+    /// then be the template, below a path prefix without markers the CDN may map to the bucket
+    /// root. A `dl` pointing at the `/sync/{crate}/{version}` route of the mirror itself always
+    /// agrees, keys are rendered from the index there whatever the template.
+    /// As cargo does, `dl` without markers means `{dl}/{crate}/{version}/download`.
+    pub fn check_dl(&self, dl: &str) -> Result<(), TemplateError> {
+        if through_sync(dl) || below_prefix(&dl_path(dl), &self.0) {
+            Ok(())
+        } else {
+            Err(TemplateError(format!(
+                "path of dl {} is neither key template {} nor {}",
+                dl_template(dl),
+                self.0,
+                SYNC_PATH
+            )))
+        }
+    }
+}
+
+/// Whether cargo, following `dl`, downloads every crate through the `/sync` route of the mirror
+pub fn through_sync(dl: &str) -> bool {
+    below_prefix(&dl_path(dl), SYNC_PATH.trim_start_matches('/'))
+}
+
+/// Whether `path` is `template` below a prefix without markers
+fn below_prefix(path: &str, template: &str) -> bool {
+    matches!(path.strip_suffix(template), Some(prefix)
+        if prefix.ends_with('/') && !MARKERS.iter().any(|marker| prefix.contains(marker)))
+}
+
+/// Path of `dl` after the host, with the markers cargo implies
+fn dl_path(dl: &str) -> String {
+    let dl = dl_template(dl);
+    match dl.find("://") {
+        Some(scheme) => dl[scheme + 3..]
+            .find('/')
+            .map_or(String::new(), |p| dl[scheme + 3 + p..].to_string()),
+        None => dl,
+    }
+}
+
+/// `dl` with the markers cargo implies when it has none
+fn dl_template(dl: &str) -> String {
+    if MARKERS.iter().any(|marker| dl.contains(marker)) {
+        dl.to_string()
+    } else {
+        format!(
+            "{}/{{crate}}/{{version}}/download",
+            dl.trim_end_matches('/')
+        )
+    }
+}
+
+/// The url cargo downloads `entry` from, following `dl` from the index config
+pub fn dl_url(dl: &str, entry: &IndexEntry) -> String {
+    render(&dl_template(dl), entry)
+}
+
+fn render(template: &str, entry: &IndexEntry) -> String {
+    let prefix = prefix(&entry.name);
+    template
+        .replace("{crate}", &entry.name)
+        .replace("{version}", &entry.vers)
+        .replace("{lowerprefix}", &prefix.to_lowercase())
+        .replace("{prefix}", &prefix)
+        .replace("{sha256-checksum}", &entry.cksum)
+}
+
+#[test]
+fn test_key_template() {
+    let entry = IndexEntry {
+        name: "Serde".to_string(),
+        vers: "1.0.0".to_string(),
+        cksum: "abcd".to_string(),
+        yanked: false,
+    };
+    let template = KeyTemplate::new("{lowerprefix}/{prefix}/{crate}/{version}/{sha256-checksum}");
+    assert_eq!(
+        template.unwrap().render(&entry),
+        "se/rd/Se/rd/Serde/1.0.0/abcd"
+    );
+    assert!(!KeyTemplate::new("{crate}/{version}/{sha256-checksum}")
+        .unwrap()
+        .is_content_addressed());
+    assert!(KeyTemplate::new("sha256/{sha256-checksum}")
+        .unwrap()
+        .is_content_addressed());
+    assert!(KeyTemplate::new("{crate}").is_err());
+    assert!(KeyTemplate::new("{crate}/{vers}").is_err());
+
+    let template = KeyTemplate::new("{crate}/{version}").unwrap();
+    assert!(template
+        .check_dl("https://cdn.example.com/{crate}/{version}")
+        .is_ok());
+    assert!(template
+        .check_dl("https://cdn.example.com/{prefix}/{crate}/{version}")
+        .is_err());
+    assert!(template
+        .check_dl("https://crates.io/api/v1/crates")
+        .is_err());
+    assert!(template
+        .check_dl("https://cdn.example.com/mirror/{crate}/{version}")
+        .is_ok());
+    let template = KeyTemplate::new("{lowerprefix}/{crate}/{crate}-{version}.crate").unwrap();
+    assert!(template
+        .check_dl("https://mirror.example.com/sync/{crate}/{version}")
+        .is_ok());
+    let template = KeyTemplate::new("sha256/{sha256-checksum}").unwrap();
+    assert!(template
+        .check_dl("https://mirror.example.com/sync/{crate}/{version}")
+        .is_ok());
+    assert!(template
+        .check_dl("https://cdn.example.com/{crate}/{version}")
+        .is_err());
+    assert!(through_sync(
+        "https://example.com/mirror/sync/{crate}/{version}"
+    ));
+    assert!(!through_sync("https://cdn.example.com/{crate}/{version}"));
+    let template = KeyTemplate::new("api/v1/crates/{crate}/{version}/download").unwrap();
+    assert!(template
+        .check_dl("https://cdn.example.com/api/v1/crates")
+        .is_ok());
+    assert_eq!(
+        dl_url("https://cdn.example.com/api/v1/crates/", &entry),
+        "https://cdn.example.com/api/v1/crates/Serde/1.0.0/download"
+    );
+}
@@ -0,0 +1,29 @@
+//! Storage clients shared by the mirror server and `sync-crates`
+#[cfg(any(feature = "upyun", feature = "obs"))]
+#[macro_use]
+extern crate log;
+#[cfg(feature = "upyun")]
+#[macro_use]
+extern crate lazy_static;
+
+use std::env;
+use std::str::FromStr;
+
+pub mod index_entry;
+pub mod key_template;
+#[cfg(any(feature = "upyun", feature = "obs"))]
+mod parts;
+pub mod retry;
+#[cfg(feature = "obs")]
+pub mod simple_obs;
+pub mod storage;
+#[cfg(feature = "upyun")]
+pub mod upyun;
+
+/// Read `key` from environment, fallback to `default` if absent or unparsable
+pub fn env_or<T: FromStr>(key: &str, default: T) -> T {
+    env::var(key)
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(default)
+}
@@ -4,26 +4,32 @@ extern crate log;
 extern crate lazy_static;
 
 use actix_web::middleware::Logger;
-use actix_web::{get, web, App, HttpResponse, HttpServer};
+use actix_web::{get, http::header, web, App, HttpResponse, HttpServer};
 use std::collections::HashMap;
 use std::env;
 use std::sync::Arc;
 use tokio::sync::{mpsc::unbounded_channel, RwLock};
 
+#[cfg(feature = "sync")]
+mod download_stats;
 mod error;
+#[cfg(feature = "sync")]
+mod failed_uploads;
 mod helper;
 #[allow(dead_code)]
 mod index;
-#[cfg(feature = "obs")]
-mod simple_obs;
-#[cfg(feature = "upyun")]
-mod upyun;
+mod negative_cache;
 #[cfg(all(feature = "systemd-integration", target_os = "linux"))]
 mod systemd;
 mod easy_git;
 
+#[cfg(feature = "sync")]
+use crate::download_stats::DownloadStats;
+use crate::error::Error;
 use crate::index::{Config, GitIndex};
-use helper::{Crate, CrateReq};
+use crate::negative_cache::NegativeCache;
+use crates_io_cn::key_template::KeyTemplate;
+use helper::{env_or, Crate, CrateReq};
 use std::ops::{Add, Deref};
 use tokio::time::{Duration, Instant};
 
@@ -33,6 +39,26 @@ lazy_static! {
     static ref DL_FORMAT: &'static str = Box::leak(env::var("DL_FORMAT").unwrap().into_boxed_str());
     static ref ACTIVE_DOWNLOADS: Arc<RwLock<HashMap<CrateReq, Arc<Crate>>>> =
         Arc::new(RwLock::new(HashMap::new()));
+    static ref NEGATIVE_CACHE: NegativeCache = NegativeCache::new(
+        Duration::from_secs(env_or("NEGATIVE_CACHE_TTL", 600)),
+        env_or("NEGATIVE_CACHE_CAPACITY", 65536),
+    );
+    static ref MAX_ACTIVE_DOWNLOADS: usize = env_or("MAX_ACTIVE_DOWNLOADS", 256);
+    /// must agree with `DL_FORMAT`, checked at startup, with only `{sha256-checksum}` to tell
+    /// versions apart each tarball is stored once
+    static ref KEY_TEMPLATE: KeyTemplate = KeyTemplate::new(
+        env::var("STORAGE_KEY_TEMPLATE").unwrap_or_else(|_| "{crate}/{version}".to_string())
+    )
+    .expect("invalid STORAGE_KEY_TEMPLATE");
+}
+
+#[cfg(feature = "sync")]
+lazy_static! {
+    /// counted into the sync database at `SYNC_DB`, if set and `DL_FORMAT` points at `/sync`
+    static ref DOWNLOAD_STATS: Option<DownloadStats> = env::var("SYNC_DB")
+        .ok()
+        .filter(|_| crates_io_cn::key_template::through_sync(*DL_FORMAT))
+        .map(DownloadStats::new);
 }
 
 ///
@@ -45,37 +71,98 @@ lazy_static! {
 /// ```
 /// Upyun will redirect 404 (non-exist) crate to given address configured
 /// replace `$_URI` with the path part `/{crate}/{version}`
+///
+/// `DL_FORMAT` may also point here directly, see `key_template::SYNC_PATH`
 #[get("/sync/{crate}/{version}")]
-async fn sync(krate_req: web::Path<CrateReq>) -> HttpResponse {
+async fn sync(krate_req: web::Path<CrateReq>) -> Result<HttpResponse, Error> {
     let krate_req = krate_req.into_inner();
-    format!("{:?}", krate_req);
-    match Crate::create(krate_req).await {
-        Err(e) => {
-            error!("{}", e);
-            HttpResponse::NotFound().finish()
+    let result = serve(krate_req.clone()).await;
+    match &result {
+        Err(Error::NotFound) => debug!("{:?} not found", krate_req),
+        Err(e) => error!("{:?}: {}", krate_req, e),
+        Ok(_) => (),
+    }
+    result
+}
+
+async fn serve(krate_req: CrateReq) -> Result<HttpResponse, Error> {
+    if NEGATIVE_CACHE.contains(&krate_req) {
+        return Err(Error::NotFound);
+    }
+    let lookup = krate_req.clone();
+    let entry = match web::block(move || index::find(*GIT_INDEX_DIR, &lookup)).await?? {
+        Some(entry) => entry,
+        None => {
+            NEGATIVE_CACHE.insert(krate_req);
+            return Err(Error::NotFound);
         }
-        Ok(krate) => {
-            let (tx, rx) = unbounded_channel::<Result<bytes::Bytes, ()>>();
-            let rx = tokio_stream::wrappers::UnboundedReceiverStream::new(rx);
-            krate.tee(tx);
-            HttpResponse::Ok()
-                .content_type("application/x-tar")
-                .streaming(rx)
+    };
+    let key = KEY_TEMPLATE.render(&entry);
+    #[cfg(feature = "sync")]
+    if let Some(stats) = DOWNLOAD_STATS.as_ref() {
+        stats.record(&key);
+    }
+    if *helper::SIGNED_URL_TTL > Duration::from_secs(0) {
+        if let Some(url) = helper::signed_url(&key, &entry.cksum).await {
+            return Ok(HttpResponse::Found()
+                .insert_header((header::LOCATION, url))
+                .finish());
         }
     }
+    let krate = Crate::create(entry).await?;
+    let (tx, rx) = unbounded_channel::<Result<bytes::Bytes, ()>>();
+    let rx = tokio_stream::wrappers::UnboundedReceiverStream::new(rx);
+    krate.tee(tx);
+    Ok(HttpResponse::Ok()
+        .content_type(krate.content_type.as_str())
+        .streaming(rx))
+}
+
+/// Prometheus text exposition of runtime gauges
+#[get("/metrics")]
+async fn metrics() -> HttpResponse {
+    let mut body = format!(
+        "# TYPE crates_active_downloads gauge\ncrates_active_downloads {}\n\
+         # TYPE storage_uploads_total counter\n",
+        ACTIVE_DOWNLOADS.read().await.len()
+    );
+    for replica in helper::STORAGES.iter() {
+        body += &replica.metrics().await;
+    }
+    HttpResponse::Ok()
+        .content_type("text/plain; version=0.0.4")
+        .body(body)
 }
 
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
     log4rs::init_file("config/log4rs.yml", Default::default()).unwrap();
     dotenv::dotenv().ok();
+    if let Err(e) = KEY_TEMPLATE.check_dl(*DL_FORMAT) {
+        panic!("STORAGE_KEY_TEMPLATE disagrees with DL_FORMAT: {}", e);
+    }
+    #[cfg(feature = "sync")]
+    if let (Ok(path), None) = (env::var("SYNC_DB"), DOWNLOAD_STATS.as_ref()) {
+        warn!("DL_FORMAT bypasses /sync, downloads served by the CDN would go uncounted");
+        if let Err(e) = download_stats::stop_counting(&path) {
+            error!("cannot mark download counts of {} incomplete: {}", path, e);
+        }
+    }
+    #[cfg(feature = "sync")]
+    if let Some(stats) = DOWNLOAD_STATS.as_ref() {
+        stats.spawn_flush(Duration::from_secs(env_or("DOWNLOAD_STATS_INTERVAL", 60)));
+    }
+    for replica in helper::STORAGES.iter() {
+        info!("replicate crates to {}", replica.name());
+        tokio::spawn(replica.storage.maintain());
+    }
     let (tx, rx) = async_channel::unbounded();
     for i in 0..10 {
         let worker_rx = rx.clone();
         tokio::spawn(async move {
             while let Ok(krate) = worker_rx.recv().await {
                 debug!("[worker#{}]start to sync {:?}", i, krate);
-                match Crate::create(krate).await {
+                match Crate::prefetch(krate).await {
                     Ok(_) => (),
                     Err(e) => error!("{}", e),
                 };
@@ -106,6 +193,7 @@ async fn main() -> std::io::Result<()> {
                 }
             };
             for krate in crates {
+                NEGATIVE_CACHE.remove(&CrateReq::from(&krate));
                 if let Err(e) = tx.send(krate).await {
                     error!("{}", e);
                 }
@@ -113,9 +201,14 @@ async fn main() -> std::io::Result<()> {
             tokio::time::sleep_until(ddl).await;
         }
     });
-    let server = HttpServer::new(|| App::new().wrap(Logger::default()).service(sync))
-        .bind("127.0.0.1:8080")?
-        .run();
+    let server = HttpServer::new(|| {
+        App::new()
+            .wrap(Logger::default())
+            .service(sync)
+            .service(metrics)
+    })
+    .bind("127.0.0.1:8080")?
+    .run();
     #[cfg(all(feature = "systemd", target_os = "linux"))]
     systemd::notify_ready();
     server.await
@@ -0,0 +1,83 @@
+use std::collections::HashMap;
+use std::sync::RwLock;
+use std::time::{Duration, Instant};
+
+use crate::helper::CrateReq;
+
+/// Remembers crates known to be missing, so repeated requests for them
+/// are answered locally instead of hitting static.crates.io again.
+pub struct NegativeCache {
+    ttl: Duration,
+    capacity: usize,
+    entries: RwLock<HashMap<CrateReq, Instant>>,
+}
+
+impl NegativeCache {
+    pub fn new(ttl: Duration, capacity: usize) -> Self {
+        Self {
+            ttl,
+            capacity,
+            entries: RwLock::new(HashMap::new()),
+        }
+    }
+
+    /// Whether `krate` was recorded missing within the last `ttl`
+    pub fn contains(&self, krate: &CrateReq) -> bool {
+        match self.entries.read().unwrap().get(&normalize(krate)) {
+            Some(since) => since.elapsed() < self.ttl,
+            None => false,
+        }
+    }
+
+    pub fn insert(&self, krate: CrateReq) {
+        let mut entries = self.entries.write().unwrap();
+        if entries.len() >= self.capacity {
+            let ttl = self.ttl;
+            entries.retain(|_, since| since.elapsed() < ttl);
+            if entries.len() >= self.capacity {
+                warn!("negative cache is full, drop {:?}", krate);
+                return;
+            }
+        }
+        entries.insert(normalize(&krate), Instant::now());
+    }
+
+    pub fn remove(&self, krate: &CrateReq) {
+        let removed = self.entries.write().unwrap().remove(&normalize(krate));
+        if removed.is_some() {
+            debug!("{:?} appeared, remove from negative cache", krate);
+        }
+    }
+}
+
+/// Crate names are looked up ignoring case and `-`/`_`, so `Serde_Json` and `serde-json`
+/// are one entry, removed together once the crate appears under its canonical name
+fn normalize(krate: &CrateReq) -> CrateReq {
+    CrateReq::new(
+        krate.name().to_lowercase().replace('_', "-"),
+        krate.version(),
+    )
+}
+
+#[test]
+fn test_negative_cache() {
+    let cache = NegativeCache::new(Duration::from_secs(60), 1);
+    let missing: CrateReq = serde_json::from_str(r#"{"name":"missing","vers":"0.1.0"}"#).unwrap();
+    let other: CrateReq = serde_json::from_str(r#"{"name":"other","vers":"0.1.0"}"#).unwrap();
+    cache.insert(missing.clone());
+    assert!(cache.contains(&missing));
+    cache.insert(other.clone());
+    assert!(!cache.contains(&other));
+    cache.remove(&missing);
+    assert!(!cache.contains(&missing));
+
+    let requested = CrateReq::new("Foo_Bar", "0.1.0");
+    cache.insert(requested.clone());
+    assert!(cache.contains(&CrateReq::new("foo-bar", "0.1.0")));
+    cache.remove(&CrateReq::new("foo_bar", "0.1.0"));
+    assert!(!cache.contains(&requested));
+
+    let expired = NegativeCache::new(Duration::from_secs(0), 1);
+    expired.insert(missing.clone());
+    assert!(!expired.contains(&missing));
+}
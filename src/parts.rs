@@ -0,0 +1,100 @@
+//! Regroup a byte stream into fixed size parts for multipart uploads
+use std::io;
+use std::pin::Pin;
+
+use bytes::{Bytes, BytesMut};
+use futures::{stream, Stream, StreamExt};
+
+/// Split `content` into parts of exactly `size` bytes, only the last one may be shorter
+///
+/// Buffers at most one part, so an upload can start before `content` is complete.
+pub fn parts<S>(content: S, size: usize) -> impl Stream<Item = io::Result<Bytes>>
+where
+    S: Stream<Item = io::Result<Bytes>>,
+{
+    let state = (Box::pin(content), BytesMut::new(), false);
+    stream::unfold(state, move |(mut content, mut buffer, failed)| async move {
+        if failed {
+            return None;
+        }
+        while buffer.len() < size {
+            match content.next().await {
+                Some(Ok(chunk)) => buffer.extend_from_slice(&chunk),
+                Some(Err(e)) => return Some((Err(e), (content, buffer, true))),
+                None => break,
+            }
+        }
+        if buffer.is_empty() {
+            return None;
+        }
+        let part = buffer.split_to(size.min(buffer.len())).freeze();
+        Some((Ok(part), (content, buffer, false)))
+    })
+}
+
+/// Content to upload, whole if it was already in memory
+pub enum Content {
+    Whole(Bytes),
+    Streaming(Pin<Box<dyn Stream<Item = io::Result<Bytes>> + Send + Sync>>),
+}
+
+/// Look at the first chunk of `content`, `length` bytes long if known
+///
+/// Content already in memory comes as one chunk of `length` bytes, or is collected when
+/// its length is unknown, and can be sent with a `Content-MD5` of the whole object.
+/// Anything else keeps streaming, its digest is only known once it has all arrived.
+pub async fn peek_whole<S>(content: S, length: Option<usize>) -> io::Result<Content>
+where
+    S: Stream<Item = io::Result<Bytes>> + Send + Sync + 'static,
+{
+    let mut content = Box::pin(content);
+    let length = match length {
+        Some(length) => length,
+        None => {
+            let mut whole = BytesMut::new();
+            while let Some(chunk) = content.next().await {
+                whole.extend_from_slice(&chunk?);
+            }
+            return Ok(Content::Whole(whole.freeze()));
+        }
+    };
+    let first = match content.next().await {
+        Some(chunk) => chunk?,
+        None => Bytes::new(),
+    };
+    if first.len() == length {
+        return Ok(Content::Whole(first));
+    }
+    Ok(Content::Streaming(Box::pin(
+        stream::once(async { Ok(first) }).chain(content),
+    )))
+}
+
+#[test]
+fn test_parts() {
+    let chunks = vec![
+        Ok(Bytes::from_static(b"abc")),
+        Ok(Bytes::from_static(b"defgh")),
+        Ok(Bytes::from_static(b"i")),
+    ];
+    let parts: Vec<_> = futures::executor::block_on(parts(stream::iter(chunks), 4).collect());
+    let parts: Vec<_> = parts.into_iter().map(Result::unwrap).collect();
+    assert_eq!(parts, vec!["abcd", "efgh", "i"]);
+}
+
+#[test]
+fn test_peek_whole() {
+    let peek = |chunks: Vec<&'static [u8]>, length| {
+        let chunks: Vec<_> = chunks
+            .into_iter()
+            .map(|c| Ok(Bytes::from_static(c)))
+            .collect();
+        futures::executor::block_on(peek_whole(stream::iter(chunks), length)).unwrap()
+    };
+    assert!(matches!(peek(vec![b"abc"], Some(3)), Content::Whole(c) if c == "abc"));
+    assert!(matches!(peek(vec![b"ab", b"c"], None), Content::Whole(c) if c == "abc"));
+    assert!(matches!(
+        peek(vec![b"ab", b"c"], Some(3)),
+        Content::Streaming(_)
+    ));
+}
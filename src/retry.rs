@@ -0,0 +1,24 @@
+//! When and how soon to retry requests to storage backends
+use std::time::Duration;
+
+use rand::Rng;
+use reqwest::StatusCode;
+
+/// Exponential backoff from 1s capped at 1min, half of it randomized
+/// so that failed requests do not retry in lockstep
+pub fn backoff(attempt: u32) -> Duration {
+    let ceiling = (1000u64 << attempt.min(6)).min(60_000);
+    Duration::from_millis(ceiling / 2 + rand::thread_rng().gen_range(0..=ceiling / 2))
+}
+
+/// Timeouts and connections refused or cut short, as opposed to malformed requests
+pub fn is_transient(e: &reqwest::Error) -> bool {
+    e.is_timeout() || e.is_connect() || e.is_request() || e.is_body()
+}
+
+/// Server side failures, request timeouts and rate limiting
+pub fn is_transient_status(status: StatusCode) -> bool {
+    status.is_server_error()
+        || status == StatusCode::REQUEST_TIMEOUT
+        || status == StatusCode::TOO_MANY_REQUESTS
+}
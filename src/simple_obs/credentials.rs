@@ -1,19 +1,13 @@
 // Modified from https://github.com/mozilla/sccache/blob/master/src/simples3/credential.rs
+use std::env;
+use std::fs;
+use std::path::PathBuf;
 
 use async_trait::async_trait;
-use chrono::{offset, DateTime, Duration, Utc};
+use chrono::{offset, DateTime, Duration, Utc, MAX_DATETIME};
 use serde::Deserialize;
-use thiserror::Error;
 
-pub type Result<T> = std::result::Result<T, ObsError>;
-
-#[derive(Debug, Error)]
-pub enum ObsError {
-    #[error("request error: {0}")]
-    Reqwest(#[from] reqwest::Error),
-    #[error("chrono error: {0}")]
-    Chrono(#[from] chrono::ParseError),
-}
+use super::error::{ObsError, Result};
 
 #[derive(Clone, Debug)]
 pub struct ObsCredentials {
@@ -24,6 +18,16 @@ pub struct ObsCredentials {
 }
 
 impl ObsCredentials {
+    /// Permanent AK/SK, with an optional security token
+    pub fn new(access: String, secret: String, security_token: Option<String>) -> Self {
+        ObsCredentials {
+            access,
+            secret,
+            security_token,
+            expires_at: MAX_DATETIME,
+        }
+    }
+
     pub fn access(&self) -> &str {
         &self.access
     }
@@ -80,6 +84,12 @@ struct OpenStackResponseDeWrapper {
     credential: OpenStackResponseDe,
 }
 
+impl Default for IamProvider {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 #[async_trait]
 impl ProvideObsCredentials for IamProvider {
     async fn credentials(&self) -> Result<ObsCredentials> {
@@ -112,44 +122,300 @@ impl ProvideObsCredentials for IamProvider {
     }
 }
 
-use std::cell::RefCell;
-use tokio::sync::Mutex;
+/// Provides a fixed set of credentials.
+pub struct StaticProvider {
+    credentials: ObsCredentials,
+}
+
+impl StaticProvider {
+    pub fn new(access: String, secret: String, security_token: Option<String>) -> Self {
+        StaticProvider {
+            credentials: ObsCredentials::new(access, secret, security_token),
+        }
+    }
+}
+
+#[async_trait]
+impl ProvideObsCredentials for StaticProvider {
+    async fn credentials(&self) -> Result<ObsCredentials> {
+        Ok(self.credentials.clone())
+    }
+}
+
+/// Provides credentials from `OBS_ACCESS_KEY_ID`, `OBS_SECRET_ACCESS_KEY`
+/// and optionally `OBS_SECURITY_TOKEN`.
+pub struct EnvironmentProvider;
+
+#[async_trait]
+impl ProvideObsCredentials for EnvironmentProvider {
+    async fn credentials(&self) -> Result<ObsCredentials> {
+        let var = |key: &str| env::var(key).ok().filter(|v| !v.is_empty());
+        match (var("OBS_ACCESS_KEY_ID"), var("OBS_SECRET_ACCESS_KEY")) {
+            (Some(access), Some(secret)) => Ok(ObsCredentials::new(
+                access,
+                secret,
+                var("OBS_SECURITY_TOKEN"),
+            )),
+            _ => Err(ObsError::Credentials(
+                "OBS_ACCESS_KEY_ID or OBS_SECRET_ACCESS_KEY not set".to_string(),
+            )),
+        }
+    }
+}
+
+/// Provides credentials from a file, `OBS_CREDENTIALS_FILE` or `~/.obs/credentials` by default
+///
+/// ```ini
+/// [default]
+/// access_key_id = AK
+/// secret_access_key = SK
+/// # optional
+/// security_token = token
+/// ```
+pub struct ProfileProvider {
+    path: PathBuf,
+}
+
+impl ProfileProvider {
+    pub fn new() -> Self {
+        let path = match env::var_os("OBS_CREDENTIALS_FILE") {
+            Some(path) => PathBuf::from(path),
+            None => env::var_os("HOME")
+                .map(PathBuf::from)
+                .unwrap_or_default()
+                .join(".obs")
+                .join("credentials"),
+        };
+        ProfileProvider { path }
+    }
+
+    pub fn with_path<P: Into<PathBuf>>(path: P) -> Self {
+        ProfileProvider { path: path.into() }
+    }
+}
+
+fn parse_profile(content: &str) -> Result<ObsCredentials> {
+    let (mut access, mut secret, mut security_token) = (None, None, None);
+    for line in content.lines().map(str::trim) {
+        if line.is_empty() || line.starts_with('#') || line.starts_with('[') {
+            continue;
+        }
+        if let Some((key, value)) = line.split_once('=') {
+            let value = Some(value.trim().to_string());
+            match key.trim() {
+                "access_key_id" => access = value,
+                "secret_access_key" => secret = value,
+                "security_token" => security_token = value,
+                _ => (),
+            }
+        }
+    }
+    match (access, secret) {
+        (Some(access), Some(secret)) => Ok(ObsCredentials::new(access, secret, security_token)),
+        _ => Err(ObsError::Credentials(
+            "access_key_id or secret_access_key missing in credentials file".to_string(),
+        )),
+    }
+}
+
+impl Default for ProfileProvider {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[async_trait]
+impl ProvideObsCredentials for ProfileProvider {
+    async fn credentials(&self) -> Result<ObsCredentials> {
+        let content = fs::read_to_string(&self.path)
+            .map_err(|e| ObsError::Credentials(format!("{}: {}", self.path.display(), e)))?;
+        parse_profile(&content)
+    }
+}
+
+/// Tries each provider in order, returning the first credentials found.
+pub struct ChainProvider {
+    providers: Vec<Box<dyn ProvideObsCredentials>>,
+}
+
+impl ChainProvider {
+    /// Environment, then credentials file, then the ECS metadata service
+    pub fn new() -> Self {
+        ChainProvider {
+            providers: vec![
+                Box::new(EnvironmentProvider),
+                Box::new(ProfileProvider::new()),
+                Box::new(IamProvider::new()),
+            ],
+        }
+    }
+
+    pub fn with_providers(providers: Vec<Box<dyn ProvideObsCredentials>>) -> Self {
+        ChainProvider { providers }
+    }
+}
+
+impl Default for ChainProvider {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[async_trait]
+impl ProvideObsCredentials for ChainProvider {
+    async fn credentials(&self) -> Result<ObsCredentials> {
+        let mut errors = Vec::new();
+        for provider in &self.providers {
+            match provider.credentials().await {
+                Ok(credentials) => return Ok(credentials),
+                Err(e) => errors.push(e.to_string()),
+            }
+        }
+        Err(ObsError::Credentials(errors.join("; ")))
+    }
+}
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+use tokio::sync::{Mutex, RwLock};
+
+/// Refresh this long before credentials expire
+const REFRESH_AHEAD: i64 = 300;
+/// Re-check credentials that never expire at this interval
+const MAX_REFRESH_INTERVAL: i64 = 3600;
 
-/// Wrapper for ProvideAwsCredentials that caches the credentials returned by the
-/// wrapped provider.  Each time the credentials are accessed, they are checked to see if
-/// they have expired, in which case they are retrieved from the wrapped provider again.
+/// Caches credentials from the wrapped provider.
+///
+/// Spawn `refresh_loop` to renew them in the background ahead of expiry; a failed
+/// refresh keeps serving the cached credentials for as long as they remain valid.
 pub struct AutoRefreshingProvider<P: ProvideObsCredentials> {
     credentials_provider: P,
-    cached_credentials: Mutex<RefCell<Option<ObsCredentials>>>,
+    cached_credentials: RwLock<Option<(ObsCredentials, DateTime<Utc>)>>,
+    refreshing: Mutex<()>,
+    failures: AtomicUsize,
+}
+
+/// Snapshot of the cached credentials, for metrics
+#[derive(Debug)]
+pub struct CredentialStats {
+    /// Time since the cached credentials were fetched
+    pub age: Option<Duration>,
+    /// Time until the cached credentials expire
+    pub expires_in: Option<Duration>,
+    /// Consecutive failed refreshes
+    pub failures: usize,
 }
 
 impl<P: ProvideObsCredentials> AutoRefreshingProvider<P> {
     pub fn new(provider: P) -> AutoRefreshingProvider<P> {
         AutoRefreshingProvider {
-            cached_credentials: Default::default(),
             credentials_provider: provider,
+            cached_credentials: Default::default(),
+            refreshing: Default::default(),
+            failures: AtomicUsize::new(0),
+        }
+    }
+
+    /// Fetch new credentials from the wrapped provider and cache them
+    pub async fn refresh(&self) -> Result<ObsCredentials> {
+        let _guard = self.refreshing.lock().await;
+        self.fetch().await
+    }
+
+    async fn fetch(&self) -> Result<ObsCredentials> {
+        match self.credentials_provider.credentials().await {
+            Ok(credentials) => {
+                self.failures.store(0, Ordering::Relaxed);
+                *self.cached_credentials.write().await = Some((credentials.clone(), Utc::now()));
+                Ok(credentials)
+            }
+            Err(e) => {
+                self.failures.fetch_add(1, Ordering::Relaxed);
+                Err(e)
+            }
+        }
+    }
+
+    /// Keep the cache fresh, refreshing `REFRESH_AHEAD` seconds before expiry and
+    /// retrying with backoff on failure. Never returns.
+    pub async fn refresh_loop(&self) {
+        loop {
+            let wait = match self.refresh().await {
+                Ok(credentials) => {
+                    let until = credentials.expires_at() - Utc::now();
+                    (until - Duration::seconds(REFRESH_AHEAD))
+                        .max(Duration::seconds(1))
+                        .min(Duration::seconds(MAX_REFRESH_INTERVAL))
+                }
+                Err(e) => {
+                    let failures = self.failures.load(Ordering::Relaxed);
+                    warn!(
+                        "fail to refresh obs credentials ({} in a row): {}",
+                        failures, e
+                    );
+                    Duration::seconds(1 << failures.min(6))
+                }
+            };
+            tokio::time::sleep(wait.to_std().unwrap()).await;
+        }
+    }
+
+    pub async fn stats(&self) -> CredentialStats {
+        let now = Utc::now();
+        let cached = self.cached_credentials.read().await;
+        CredentialStats {
+            age: cached.as_ref().map(|(_, fetched_at)| now - *fetched_at),
+            expires_in: cached
+                .as_ref()
+                .map(|(credentials, _)| credentials.expires_at() - now),
+            failures: self.failures.load(Ordering::Relaxed),
         }
     }
+
+    async fn cached(&self) -> Option<ObsCredentials> {
+        self.cached_credentials
+            .read()
+            .await
+            .as_ref()
+            .map(|(credentials, _)| credentials.clone())
+    }
 }
 
 #[async_trait]
 impl<P: ProvideObsCredentials> ProvideObsCredentials for AutoRefreshingProvider<P> {
     async fn credentials(&self) -> Result<ObsCredentials> {
-        let guard = self.cached_credentials.lock().await;
-        let is_invalid = match guard.borrow().as_ref() {
-            Some(credentials) => credentials.credentials_are_expired(),
-            None => true,
-        };
-        return if is_invalid {
-            match self.credentials_provider.credentials().await {
-                Ok(credentials) => {
-                    guard.replace(Some(credentials.clone()));
+        match self.cached().await {
+            Some(credentials) if !credentials.credentials_are_expired() => return Ok(credentials),
+            _ => (),
+        }
+        let _guard = self.refreshing.lock().await;
+        // another task may have refreshed while we waited
+        let cached = self.cached().await;
+        match &cached {
+            Some(credentials) if !credentials.credentials_are_expired() => {
+                return Ok(credentials.clone())
+            }
+            _ => (),
+        }
+        match self.fetch().await {
+            Ok(credentials) => Ok(credentials),
+            Err(e) => match cached {
+                Some(credentials) if credentials.expires_at() > Utc::now() => {
+                    warn!("fail to refresh obs credentials, use cached ones: {}", e);
                     Ok(credentials)
                 }
-                Err(e) => Err(e),
-            }
-        } else {
-            Ok(guard.borrow().as_ref().unwrap().clone())
-        };
+                _ => Err(e),
+            },
+        }
     }
 }
+
+#[test]
+fn test_parse_profile() {
+    let credentials =
+        parse_profile("[default]\n# comment\naccess_key_id = AK\nsecret_access_key=SK\n").unwrap();
+    assert_eq!(credentials.access(), "AK");
+    assert_eq!(credentials.secret(), "SK");
+    assert!(credentials.security_token().is_none());
+    assert!(!credentials.credentials_are_expired());
+    assert!(parse_profile("access_key_id = AK").is_err());
+}
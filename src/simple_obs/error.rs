@@ -0,0 +1,133 @@
+use reqwest::{Response, StatusCode};
+use serde::Deserialize;
+use thiserror::Error;
+
+use crate::retry;
+
+pub type Result<T> = std::result::Result<T, ObsError>;
+
+#[derive(Debug, Error)]
+pub enum ObsError {
+    #[error("request error: {0}")]
+    Reqwest(#[from] reqwest::Error),
+    #[error("chrono error: {0}")]
+    Chrono(#[from] chrono::ParseError),
+    #[error("xml error: {0}")]
+    Xml(#[from] quick_xml::DeError),
+    #[error("read content: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("missing header {0}")]
+    MissingHeader(&'static str),
+    #[error("credentials unavailable: {0}")]
+    Credentials(String),
+    #[error("obs error {status} {code}: {message}, x-obs-request-id: {request_id}")]
+    Service {
+        status: StatusCode,
+        code: String,
+        message: String,
+        request_id: String,
+    },
+}
+
+/// https://support.huaweicloud.com/api-obs/obs_04_0115.html
+///
+/// ```xml
+/// <Error>
+///   <Code>SignatureDoesNotMatch</Code>
+///   <Message>...</Message>
+///   <RequestId>...</RequestId>
+///   <HostId>...</HostId>
+/// </Error>
+/// ```
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "PascalCase", default)]
+struct ErrorBody {
+    code: String,
+    message: String,
+    request_id: String,
+}
+
+impl ObsError {
+    /// Build a `Service` error from a non-2xx response
+    ///
+    /// Responses without a body (e.g. to `HEAD`) carry the code in `x-obs-error-code`.
+    pub async fn from_response(resp: Response) -> Self {
+        let status = resp.status();
+        let header = |name: &str| {
+            resp.headers()
+                .get(name)
+                .and_then(|v| v.to_str().ok())
+                .unwrap_or_default()
+                .to_string()
+        };
+        let code = header("x-obs-error-code");
+        let request_id = header("x-obs-request-id");
+        let body = resp.text().await.unwrap_or_default();
+        let body: ErrorBody = quick_xml::de::from_str(&body).unwrap_or_default();
+        ObsError::Service {
+            status,
+            code: if body.code.is_empty() {
+                code
+            } else {
+                body.code
+            },
+            message: body.message,
+            request_id: if body.request_id.is_empty() {
+                request_id
+            } else {
+                body.request_id
+            },
+        }
+    }
+}
+
+impl ObsError {
+    /// Transient transport failures and statuses, or an OBS error code marked transient
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            ObsError::Reqwest(e) => retry::is_transient(e),
+            ObsError::Chrono(_)
+            | ObsError::Xml(_)
+            | ObsError::Io(_)
+            | ObsError::MissingHeader(_)
+            | ObsError::Credentials(_) => false,
+            ObsError::Service { status, code, .. } => {
+                is_retryable_code(code) || retry::is_transient_status(*status)
+            }
+        }
+    }
+}
+
+/// https://support.huaweicloud.com/api-obs/obs_04_0115.html
+///
+/// Error codes of the XML error body that describe a transient condition
+const RETRYABLE_CODES: &[&str] = &[
+    "InternalError",
+    "RequestTimeout",
+    "ServiceUnavailable",
+    "SlowDown",
+    "OperationAborted",
+    "IncompleteBody",
+    "InvalidDigest",
+    "BadDigest",
+];
+
+pub fn is_retryable_code(code: &str) -> bool {
+    RETRYABLE_CODES.contains(&code)
+}
+
+#[test]
+fn test_error_body() {
+    let body = r#"<?xml version="1.0" encoding="UTF-8" standalone="yes"?>
+<Error>
+  <Code>SignatureDoesNotMatch</Code>
+  <Message>The request signature we calculated does not match the signature you provided.</Message>
+  <RequestId>0000016B2B8BDE0C</RequestId>
+  <HostId>obs.example.com</HostId>
+</Error>"#;
+    let body: ErrorBody = quick_xml::de::from_str(body).unwrap();
+    assert_eq!(body.code, "SignatureDoesNotMatch");
+    assert_eq!(body.request_id, "0000016B2B8BDE0C");
+    assert!(!is_retryable_code(&body.code));
+    assert!(is_retryable_code("SlowDown"));
+}
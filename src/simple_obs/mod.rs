@@ -1,4 +1,5 @@
 mod credentials;
+pub mod error;
 mod obs;
 
 pub use credentials::*;
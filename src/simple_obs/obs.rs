@@ -1,12 +1,23 @@
 // Modified from https://github.com/mozilla/sccache/blob/master/src/simples3/s3.rs
+#![allow(dead_code)]
 use core::fmt;
 
+use std::collections::HashMap;
+use std::io;
+
 use bytes::Bytes;
+use chrono::Utc;
+use futures::{stream, Stream, StreamExt, TryStreamExt};
 use hmac::{Hmac, Mac, NewMac};
-use reqwest::Response;
+use md5::{Digest, Md5};
+use reqwest::{header, Body, Method, RequestBuilder, Response, StatusCode, Url};
+use serde::Deserialize;
 use sha1::Sha1;
 
 use super::credentials::*;
+use super::error::{ObsError, Result};
+use crate::parts::{parts, peek_whole, Content};
+use crate::retry;
 
 #[derive(Debug, Copy, Clone)]
 #[allow(dead_code)]
@@ -40,12 +51,82 @@ fn signature(string_to_sign: &str, signing_key: &str) -> String {
     base64::encode(&s)
 }
 
+/// `meta` as `x-obs-meta-*` headers
+fn meta_headers(meta: &HashMap<String, String>) -> Vec<(String, String)> {
+    meta.iter()
+        .map(|(key, value)| (format!("{}{}", META_PREFIX, key), value.clone()))
+        .collect()
+}
+
+/// Turn a non-2xx response into `ObsError::Service`
+async fn check(resp: Response) -> Result<Response> {
+    if resp.status().is_success() {
+        Ok(resp)
+    } else {
+        Err(ObsError::from_response(resp).await)
+    }
+}
+
+const OCTET_STREAM: &str = "application/octet-stream";
+const XML: &str = "application/xml";
+const META_PREFIX: &str = "x-obs-meta-";
+
+/// Parts but the last must be at least 100 KiB
+const PART_SIZE: usize = 5 * 1024 * 1024;
+const PART_ATTEMPTS: u32 = 3;
+const PART_CONCURRENCY: usize = 4;
+
+/// Metadata returned by `HEAD` on an object
+#[derive(Debug, Clone)]
+pub struct ObjectInfo {
+    pub size: u64,
+    pub etag: Option<String>,
+    pub content_type: Option<String>,
+    /// absent for `STANDARD`
+    pub storage_class: Option<String>,
+    /// `x-obs-restore` of a `COLD` object, `ongoing-request="false"` once it is readable
+    pub restore: Option<String>,
+    /// `x-obs-meta-*` headers, keyed without the prefix
+    pub meta: HashMap<String, String>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct ObjectSummary {
+    pub key: String,
+    pub size: u64,
+    #[serde(rename = "ETag")]
+    pub etag: String,
+    pub last_modified: String,
+    #[serde(default)]
+    pub storage_class: Option<String>,
+}
+
+/// https://support.huaweicloud.com/api-obs/obs_04_0022.html
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct ListObjectsPage {
+    #[serde(rename = "Contents", default)]
+    pub contents: Vec<ObjectSummary>,
+    #[serde(default)]
+    pub is_truncated: bool,
+    #[serde(default)]
+    pub next_marker: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct InitiateMultipartUploadResult {
+    upload_id: String,
+}
+
 /// An Obs bucket.
 pub struct Bucket {
     name: String,
     base_url: String,
     host: String,
     client: reqwest::Client,
+    multipart_threshold: usize,
 }
 
 impl fmt::Display for Bucket {
@@ -56,50 +137,464 @@ impl fmt::Display for Bucket {
 
 impl Bucket {
     pub fn new(name: &str, endpoint: &str, ssl: Ssl) -> Bucket {
-        let base_url = base_url(&endpoint, ssl);
+        let base_url = base_url(endpoint, ssl);
         Bucket {
             name: name.to_owned(),
             base_url,
             host: format!("{}.{}", &name, &endpoint),
             client: reqwest::Client::new(),
+            multipart_threshold: usize::MAX,
         }
     }
 
-    pub async fn put(&self, key: &str, content: Bytes, creds: &ObsCredentials) -> Result<Response> {
-        use chrono::Utc;
-        use reqwest::header;
+    /// Objects larger than `threshold` bytes are sent with the multipart upload API
+    pub fn set_multipart_threshold(&mut self, threshold: usize) {
+        self.multipart_threshold = threshold;
+    }
 
-        let url = format!("{}{}", self.base_url, key);
-        debug!("PUT {}", url);
-        let request = self.client.put(&url);
+    pub async fn put(&self, key: &str, content: Bytes, creds: &ObsCredentials) -> Result<()> {
+        let length = content.len();
+        let content = stream::iter(vec![Ok(content)]);
+        self.put_stream(key, content, Some(length), &HashMap::new(), creds)
+            .await
+    }
 
-        let content_type = "application/octet-stream";
-        let date = Utc::now().format("%a, %d %b %Y %H:%M:%S GMT").to_string();
-        let mut canonical_headers = String::new();
-        let request = if let Some(ref token) = creds.security_token() {
-            canonical_headers.push_str(format!("{}:{}\n", "x-obs-security-token", token).as_ref());
-            request.header("x-obs-security-token", token)
-        } else {
-            request
+    /// Upload `content` as it arrives, attaching `meta` as `x-obs-meta-*` headers
+    ///
+    /// Content above the multipart threshold goes through the multipart API, where every
+    /// part carries its own `Content-MD5`. Smaller content already in memory is sent with
+    /// a `Content-MD5` of the whole object, and streamed without one otherwise, callers
+    /// check the stored size and checksum afterwards. Content of unknown `length` is
+    /// collected first, as it is for Upyun.
+    pub async fn put_stream<S>(
+        &self,
+        key: &str,
+        content: S,
+        length: Option<usize>,
+        meta: &HashMap<String, String>,
+        creds: &ObsCredentials,
+    ) -> Result<()>
+    where
+        S: Stream<Item = io::Result<Bytes>> + Send + Sync + 'static,
+    {
+        let mut headers = meta_headers(meta);
+        let (body, length) = match peek_whole(content, length).await? {
+            Content::Whole(content) if content.len() > self.multipart_threshold => {
+                let content = stream::iter(vec![Ok(content)]);
+                return self.put_multipart(key, content, meta, creds).await;
+            }
+            Content::Whole(content) => {
+                headers.push((
+                    "content-md5".to_string(),
+                    base64::encode(Md5::digest(&content)),
+                ));
+                let length = content.len();
+                (Body::from(content), length)
+            }
+            Content::Streaming(content) => {
+                let length = length.unwrap_or_default();
+                if length > self.multipart_threshold {
+                    return self.put_multipart(key, content, meta, creds).await;
+                }
+                (Body::wrap_stream(content), length)
+            }
+        };
+        let headers: Vec<(&str, &str)> = headers
+            .iter()
+            .map(|(name, value)| (name.as_str(), value.as_str()))
+            .collect();
+        let request = self
+            .request(Method::PUT, key, None, OCTET_STREAM, &headers, creds)
+            .header(header::CONTENT_LENGTH, length)
+            .body(body);
+        self.send(request).await?;
+        Ok(())
+    }
+
+    pub async fn get(&self, key: &str, creds: &ObsCredentials) -> Result<Response> {
+        let request = self.request(Method::GET, key, None, "", &[], creds);
+        self.send(request).await
+    }
+
+    /// Returns `None` if the object does not exist
+    pub async fn head(&self, key: &str, creds: &ObsCredentials) -> Result<Option<ObjectInfo>> {
+        let request = self.request(Method::HEAD, key, None, "", &[], creds);
+        let resp = match self.send(request).await {
+            Ok(resp) => resp,
+            Err(ObsError::Service { status, .. }) if status == StatusCode::NOT_FOUND => {
+                return Ok(None)
+            }
+            Err(e) => return Err(e),
+        };
+        let headers = resp.headers();
+        let get = |name: &str| {
+            headers
+                .get(name)
+                .and_then(|v| v.to_str().ok())
+                .map(str::to_string)
+        };
+        let meta = headers
+            .iter()
+            .filter_map(|(name, value)| {
+                let key = name.as_str().strip_prefix(META_PREFIX)?;
+                Some((key.to_string(), value.to_str().ok()?.to_string()))
+            })
+            .collect();
+        Ok(Some(ObjectInfo {
+            // the body of a HEAD response is empty, so `content_length` would be 0
+            size: get(header::CONTENT_LENGTH.as_str())
+                .and_then(|s| s.parse().ok())
+                .unwrap_or_default(),
+            etag: get(header::ETAG.as_str()),
+            content_type: get(header::CONTENT_TYPE.as_str()),
+            storage_class: get("x-obs-storage-class"),
+            restore: get("x-obs-restore"),
+            meta,
+        }))
+    }
+
+    /// Move `key` to `STANDARD`, `WARM` or `COLD`, keeping its content and metadata
+    ///
+    /// https://support.huaweicloud.com/api-obs/obs_04_0091.html
+    pub async fn set_storage_class(
+        &self,
+        key: &str,
+        storage_class: &str,
+        creds: &ObsCredentials,
+    ) -> Result<()> {
+        let headers = [
+            ("x-obs-metadata-directive", "REPLACE_NEW"),
+            ("x-obs-storage-class", storage_class),
+        ];
+        let request = self.request(Method::PUT, key, Some("metadata"), "", &headers, creds);
+        self.send(request).await?;
+        Ok(())
+    }
+
+    /// Make a `COLD` object readable for `days`, `tier` is `Expedited` or `Standard`
+    ///
+    /// https://support.huaweicloud.com/api-obs/obs_04_0084.html
+    pub async fn restore(
+        &self,
+        key: &str,
+        days: u32,
+        tier: &str,
+        creds: &ObsCredentials,
+    ) -> Result<()> {
+        let body = format!(
+            "<RestoreRequest><Days>{}</Days><RestoreJob><Tier>{}</Tier></RestoreJob></RestoreRequest>",
+            days, tier
+        );
+        let md5 = base64::encode(Md5::digest(body.as_bytes()));
+        let headers = [("content-md5", md5.as_str())];
+        let request = self
+            .request(Method::POST, key, Some("restore"), XML, &headers, creds)
+            .body(body);
+        self.send(request).await?;
+        Ok(())
+    }
+
+    pub async fn delete(&self, key: &str, creds: &ObsCredentials) -> Result<()> {
+        let request = self.request(Method::DELETE, key, None, "", &[], creds);
+        self.send(request).await?;
+        Ok(())
+    }
+
+    /// List up to `max_keys` objects under `prefix` after `marker`,
+    /// continue with `next_marker` while the page `is_truncated`
+    pub async fn list(
+        &self,
+        prefix: &str,
+        marker: Option<&str>,
+        max_keys: usize,
+        creds: &ObsCredentials,
+    ) -> Result<ListObjectsPage> {
+        let mut query = vec![
+            ("prefix", prefix.to_string()),
+            ("max-keys", max_keys.to_string()),
+        ];
+        if let Some(marker) = marker {
+            query.push(("marker", marker.to_string()));
+        }
+        let request = self
+            .request(Method::GET, "", None, "", &[], creds)
+            .query(&query);
+        let body = self.send(request).await?.text().await?;
+        let mut page: ListObjectsPage = quick_xml::de::from_str(&body)?;
+        // NextMarker is only returned when a delimiter is set
+        if page.is_truncated && page.next_marker.is_none() {
+            page.next_marker = page.contents.last().map(|object| object.key.clone());
+        }
+        Ok(page)
+    }
+
+    /// Upload `content` in parts, a failed part is retried on its own
+    /// and the upload is aborted if it cannot be completed
+    pub async fn put_multipart<S>(
+        &self,
+        key: &str,
+        content: S,
+        meta: &HashMap<String, String>,
+        creds: &ObsCredentials,
+    ) -> Result<()>
+    where
+        S: Stream<Item = io::Result<Bytes>>,
+    {
+        let upload_id = self.initiate_multipart(key, meta, creds).await?;
+        debug!("{} multipart upload {} initiated", key, upload_id);
+        let upload_id_ref = &upload_id;
+        let result = parts(content, PART_SIZE)
+            .enumerate()
+            .map(|(index, part)| async move {
+                let number = index as u32 + 1;
+                let etag = self
+                    .upload_part_with_retry(key, upload_id_ref, number, part?, creds)
+                    .await?;
+                Ok((number, etag))
+            })
+            .buffer_unordered(PART_CONCURRENCY)
+            .try_collect::<Vec<_>>()
+            .await;
+        let result = match result {
+            Ok(mut parts) => {
+                parts.sort_by_key(|(number, _)| *number);
+                self.complete_multipart(key, &upload_id, &parts, creds)
+                    .await
+            }
+            Err(e) => Err(e),
+        };
+        if let Err(e) = result {
+            if let Err(abort) = self.abort_multipart(key, &upload_id, creds).await {
+                warn!("{} abort multipart upload {}: {}", key, upload_id, abort);
+            }
+            return Err(e);
+        }
+        debug!("{} multipart upload {} completed", key, upload_id);
+        Ok(())
+    }
+
+    /// Returns the upload id, `meta` is attached to the completed object
+    pub async fn initiate_multipart(
+        &self,
+        key: &str,
+        meta: &HashMap<String, String>,
+        creds: &ObsCredentials,
+    ) -> Result<String> {
+        let headers = meta_headers(meta);
+        let headers: Vec<(&str, &str)> = headers
+            .iter()
+            .map(|(name, value)| (name.as_str(), value.as_str()))
+            .collect();
+        let request = self.request(
+            Method::POST,
+            key,
+            Some("uploads"),
+            OCTET_STREAM,
+            &headers,
+            creds,
+        );
+        let body = self.send(request).await?.text().await?;
+        let InitiateMultipartUploadResult { upload_id } = quick_xml::de::from_str(&body)?;
+        Ok(upload_id)
+    }
+
+    /// Returns the ETag of the part, `number` starts from 1
+    pub async fn upload_part(
+        &self,
+        key: &str,
+        upload_id: &str,
+        number: u32,
+        content: Bytes,
+        creds: &ObsCredentials,
+    ) -> Result<String> {
+        let sub_resource = format!("partNumber={}&uploadId={}", number, upload_id);
+        let md5 = base64::encode(Md5::digest(&content));
+        let headers = [("content-md5", md5.as_str())];
+        let request = self
+            .request(Method::PUT, key, Some(&sub_resource), "", &headers, creds)
+            .header(header::CONTENT_LENGTH, content.len())
+            .body(content);
+        let resp = self.send(request).await?;
+        resp.headers()
+            .get(header::ETAG)
+            .and_then(|v| v.to_str().ok())
+            .map(str::to_string)
+            .ok_or(ObsError::MissingHeader("ETag"))
+    }
+
+    async fn upload_part_with_retry(
+        &self,
+        key: &str,
+        upload_id: &str,
+        number: u32,
+        content: Bytes,
+        creds: &ObsCredentials,
+    ) -> Result<String> {
+        let mut attempt = 1;
+        loop {
+            match self
+                .upload_part(key, upload_id, number, content.clone(), creds)
+                .await
+            {
+                Err(e) if e.is_retryable() && attempt < PART_ATTEMPTS => {
+                    let delay = retry::backoff(attempt);
+                    warn!(
+                        "{} part {} attempt {} failed: {}, retry in {:?}",
+                        key, number, attempt, e, delay
+                    );
+                    attempt += 1;
+                    tokio::time::sleep(delay).await;
+                }
+                result => return result,
+            }
+        }
+    }
+
+    /// `parts` are `(number, etag)` in ascending order
+    pub async fn complete_multipart(
+        &self,
+        key: &str,
+        upload_id: &str,
+        parts: &[(u32, String)],
+        creds: &ObsCredentials,
+    ) -> Result<()> {
+        let mut body = String::from("<CompleteMultipartUpload>");
+        for (number, etag) in parts {
+            body.push_str(&format!(
+                "<Part><PartNumber>{}</PartNumber><ETag>{}</ETag></Part>",
+                number, etag
+            ));
+        }
+        body.push_str("</CompleteMultipartUpload>");
+        let sub_resource = format!("uploadId={}", upload_id);
+        let request = self
+            .request(Method::POST, key, Some(&sub_resource), XML, &[], creds)
+            .body(body);
+        self.send(request).await?;
+        Ok(())
+    }
+
+    pub async fn abort_multipart(
+        &self,
+        key: &str,
+        upload_id: &str,
+        creds: &ObsCredentials,
+    ) -> Result<()> {
+        let sub_resource = format!("uploadId={}", upload_id);
+        let request = self.request(Method::DELETE, key, Some(&sub_resource), "", &[], creds);
+        self.send(request).await?;
+        Ok(())
+    }
+
+    /// Build a signed request for `key`, an empty key addresses the bucket
+    ///
+    /// `sub_resource` (e.g. `uploads`) is part of the signed resource, while plain
+    /// query parameters should be appended to the returned builder.
+    /// `Content-MD5` and `x-obs-*` among `headers` are covered by the signature.
+    fn request(
+        &self,
+        method: Method,
+        key: &str,
+        sub_resource: Option<&str>,
+        content_type: &str,
+        headers: &[(&str, &str)],
+        creds: &ObsCredentials,
+    ) -> RequestBuilder {
+        let resource = match sub_resource {
+            Some(sub_resource) => format!("{}?{}", key, sub_resource),
+            None => key.to_string(),
         };
+        let url = format!("{}{}", self.base_url, resource);
+        debug!("{} {}", method, url);
+
+        let date = Utc::now().format("%a, %d %b %Y %H:%M:%S GMT").to_string();
+        let mut obs_headers: Vec<(String, &str)> = headers
+            .iter()
+            .map(|(name, value)| (name.to_lowercase(), *value))
+            .filter(|(name, _)| name.starts_with("x-obs-"))
+            .collect();
+        if let Some(ref token) = creds.security_token() {
+            obs_headers.push(("x-obs-security-token".to_string(), token));
+        }
+        obs_headers.sort();
+        let canonical_headers: String = obs_headers
+            .iter()
+            .map(|(name, value)| format!("{}:{}\n", name, value))
+            .collect();
+        let md5 = headers
+            .iter()
+            .find(|(name, _)| name.eq_ignore_ascii_case("content-md5"))
+            .map_or("", |(_, value)| *value);
 
         let auth = self.auth(
-            "PUT",
+            method.as_str(),
             &date,
-            key,
-            "",
+            &resource,
+            md5,
             &canonical_headers,
             content_type,
             creds,
         );
-        let request = request
+        let mut request = self
+            .client
+            .request(method, &url)
             .header(header::DATE, date)
             .header(header::HOST, &self.host)
-            .header(header::CONTENT_TYPE, content_type)
-            .header(header::CONTENT_LENGTH, content.len())
-            .header(header::AUTHORIZATION, auth)
-            .body(content);
+            .header(header::AUTHORIZATION, auth);
+        if !content_type.is_empty() {
+            request = request.header(header::CONTENT_TYPE, content_type);
+        }
+        if let Some(ref token) = creds.security_token() {
+            request = request.header("x-obs-security-token", token);
+        }
+        for (name, value) in headers {
+            request = request.header(*name, *value);
+        }
+        request
+    }
+
+    /// A GET url for `key` valid for `expires_in`, using query string authentication
+    ///
+    /// https://support.huaweicloud.com/api-obs/obs_04_0011.html
+    pub fn presigned_url(
+        &self,
+        key: &str,
+        expires_in: std::time::Duration,
+        creds: &ObsCredentials,
+    ) -> String {
+        let expires = Utc::now().timestamp() + expires_in.as_secs() as i64;
+        self.presigned_url_at(key, expires, creds)
+    }
+
+    fn presigned_url_at(&self, key: &str, expires: i64, creds: &ObsCredentials) -> String {
+        let headers = match creds.security_token() {
+            Some(token) => format!("x-obs-security-token:{}\n", token),
+            None => String::new(),
+        };
+        let string = format!(
+            "GET\n\n\n{expires}\n{headers}/{bucket}/{key}",
+            expires = expires,
+            headers = headers,
+            bucket = self.name,
+            key = key
+        );
+        let scheme = self.base_url.split("://").next().unwrap_or("https");
+        let mut url =
+            Url::parse(&format!("{}://{}/{}", scheme, self.host, key)).expect("invalid object url");
+        {
+            let mut query = url.query_pairs_mut();
+            query
+                .append_pair("AccessKeyId", creds.access())
+                .append_pair("Expires", &expires.to_string())
+                .append_pair("Signature", &signature(&string, creds.secret()));
+            if let Some(token) = creds.security_token() {
+                query.append_pair("x-obs-security-token", token);
+            }
+        }
+        url.to_string()
+    }
 
+    async fn send(&self, request: RequestBuilder) -> Result<Response> {
         let result = request.send().await?;
         let headers = result.headers();
         let request_id = headers
@@ -114,7 +609,7 @@ impl Bucket {
             request_id,
             obs_id
         );
-        Ok(result)
+        check(result).await
     }
 
     /// https://support.huaweicloud.com/api-obs/obs_04_0010.html
@@ -150,15 +645,59 @@ impl Bucket {
         creds: &ObsCredentials,
     ) -> String {
         let string = format!(
-            "{verb}\n{md5}\n{ty}\n{date}\n{headers}{resource}",
+            "{verb}\n{md5}\n{ty}\n{date}\n{headers}/{bucket}/{path}",
             verb = verb,
             md5 = md5,
             ty = content_type,
             date = date,
             headers = headers,
-            resource = format!("/{}/{}", self.name, path)
+            bucket = self.name,
+            path = path
         );
         let signature = signature(&string, creds.secret());
         format!("OBS {}:{}", creds.access(), signature)
     }
 }
+
+#[test]
+fn test_presigned_url() {
+    let bucket = Bucket::new("bucket", "obs.example.com", Ssl::Yes);
+    let creds = ObsCredentials::new("ak".to_string(), "secret".to_string(), None);
+    assert_eq!(
+        bucket.presigned_url_at("serde/1.0.0", 1600000000, &creds),
+        "https://bucket.obs.example.com/serde/1.0.0\
+         ?AccessKeyId=ak&Expires=1600000000&Signature=L4tr4mYGNQzsyq6IBRFq3pqLb%2B8%3D"
+    );
+}
+
+#[test]
+fn test_list_objects_page() {
+    let body = r#"<?xml version="1.0" encoding="UTF-8" standalone="yes"?>
+<ListBucketResult xmlns="http://obs.myhwclouds.com/doc/2015-06-30/">
+  <Name>crates</Name>
+  <Prefix>serde/</Prefix>
+  <Marker></Marker>
+  <MaxKeys>2</MaxKeys>
+  <IsTruncated>true</IsTruncated>
+  <Contents>
+    <Key>serde/1.0.0</Key>
+    <LastModified>2021-03-01T08:00:00.000Z</LastModified>
+    <ETag>"3b2a0d1c5e5a8f44c2b0a1b2c3d4e5f6"</ETag>
+    <Size>73548</Size>
+    <StorageClass>STANDARD</StorageClass>
+  </Contents>
+  <Contents>
+    <Key>serde/1.0.1</Key>
+    <LastModified>2021-03-01T08:00:01.000Z</LastModified>
+    <ETag>"0f1e2d3c4b5a69788796a5b4c3d2e1f0"</ETag>
+    <Size>73612</Size>
+    <StorageClass>WARM</StorageClass>
+  </Contents>
+</ListBucketResult>"#;
+    let page: ListObjectsPage = quick_xml::de::from_str(body).unwrap();
+    assert!(page.is_truncated);
+    assert_eq!(page.contents.len(), 2);
+    assert_eq!(page.contents[1].key, "serde/1.0.1");
+    assert_eq!(page.contents[1].size, 73612);
+    assert_eq!(page.contents[1].storage_class.as_deref(), Some("WARM"));
+}
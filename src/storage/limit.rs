@@ -0,0 +1,72 @@
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use futures::StreamExt;
+
+use super::ByteStream;
+
+/// Token bucket shared by every upload to a backend, refilled at `bps` bytes per second
+///
+/// A chunk larger than what is left goes through at once and puts the bucket in debt,
+/// so later chunks wait until it is paid back. Bursts are capped at one second worth.
+pub struct RateLimiter {
+    bps: f64,
+    /// available bytes, negative while in debt, and when they were counted
+    state: Mutex<(f64, Instant)>,
+}
+
+impl RateLimiter {
+    pub fn new(bps: u64) -> Self {
+        RateLimiter {
+            bps: bps as f64,
+            state: Mutex::new((bps as f64, Instant::now())),
+        }
+    }
+
+    /// Take `bytes` from the bucket, waiting if it is in debt afterwards
+    pub async fn acquire(&self, bytes: usize) {
+        let wait = {
+            let mut state = self.state.lock().unwrap();
+            let (ref mut tokens, ref mut since) = *state;
+            let now = Instant::now();
+            let refill = now.duration_since(*since).as_secs_f64() * self.bps;
+            *tokens = (*tokens + refill).min(self.bps) - bytes as f64;
+            *since = now;
+            if *tokens < 0.0 {
+                Duration::from_secs_f64(-*tokens / self.bps)
+            } else {
+                Duration::from_secs(0)
+            }
+        };
+        if wait > Duration::from_secs(0) {
+            tokio::time::sleep(wait).await;
+        }
+    }
+}
+
+/// `content` with every chunk passed through `limiter` before it is yielded
+pub fn throttle(content: ByteStream, limiter: Arc<RateLimiter>) -> ByteStream {
+    Box::pin(content.then(move |chunk| {
+        let limiter = limiter.clone();
+        async move {
+            if let Ok(ref chunk) = chunk {
+                limiter.acquire(chunk.len()).await;
+            }
+            chunk
+        }
+    }))
+}
+
+#[test]
+fn test_rate_limiter() {
+    let limiter = RateLimiter::new(1000);
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    let start = Instant::now();
+    runtime.block_on(async {
+        // the first second worth is a burst, the rest is paced
+        limiter.acquire(1000).await;
+        assert!(start.elapsed() < Duration::from_millis(100));
+        limiter.acquire(200).await;
+    });
+    assert!(start.elapsed() >= Duration::from_millis(200));
+}
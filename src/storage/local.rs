@@ -0,0 +1,150 @@
+use std::path::{Path, PathBuf};
+
+use async_trait::async_trait;
+use bytes::Bytes;
+use futures::stream::{self, BoxStream};
+use futures::StreamExt;
+use sha2::{Digest, Sha256};
+use tokio::fs;
+use tokio::io::AsyncWriteExt;
+
+use super::{required, ByteStream, ObjectEntry, ObjectMeta, Result, Storage, StorageClass};
+
+/// Files under `LOCAL_STORAGE_DIR`, laid out by key
+pub struct LocalStorage {
+    root: PathBuf,
+}
+
+impl LocalStorage {
+    pub fn new<P: Into<PathBuf>>(root: P) -> Self {
+        LocalStorage { root: root.into() }
+    }
+
+    pub fn from_env() -> Result<Self> {
+        Ok(Self::new(required("LOCAL_STORAGE_DIR")?))
+    }
+
+    fn path(&self, key: &str) -> PathBuf {
+        self.root.join(key)
+    }
+}
+
+/// Files under `dir` recursively, skipping hidden ones such as unfinished uploads
+fn walk(root: &Path, dir: &Path, entries: &mut Vec<ObjectEntry>) -> std::io::Result<()> {
+    for entry in std::fs::read_dir(dir)? {
+        let entry = entry?;
+        if entry.file_name().to_string_lossy().starts_with('.') {
+            continue;
+        }
+        let metadata = entry.metadata()?;
+        let path = entry.path();
+        if metadata.is_dir() {
+            walk(root, &path, entries)?;
+        } else if let Ok(key) = path.strip_prefix(root) {
+            entries.push(ObjectEntry {
+                key: key.to_string_lossy().replace('\\', "/"),
+                size: metadata.len(),
+            });
+        }
+    }
+    Ok(())
+}
+
+#[async_trait]
+impl Storage for LocalStorage {
+    fn name(&self) -> &str {
+        "local"
+    }
+
+    /// Written to a hidden temporary file first, so a crate is never seen half written
+    async fn put(
+        &self,
+        key: &str,
+        mut content: ByteStream,
+        _length: Option<usize>,
+        _sha256: &str,
+    ) -> Result<()> {
+        let path = self.path(key);
+        let dir = path.parent().unwrap_or(&self.root);
+        fs::create_dir_all(dir).await?;
+        let file_name = path.file_name().unwrap_or_default().to_string_lossy();
+        // one per writer, a blob may be uploaded by two downloads or processes at once
+        let tmp = dir.join(format!(
+            ".{}.{}-{:08x}.tmp",
+            file_name,
+            std::process::id(),
+            rand::random::<u32>()
+        ));
+        let mut file = fs::File::create(&tmp).await?;
+        while let Some(chunk) = content.next().await {
+            let written = match chunk {
+                Ok(chunk) => file.write_all(&chunk).await,
+                Err(e) => Err(e),
+            };
+            if let Err(e) = written {
+                drop(file);
+                fs::remove_file(&tmp).await.ok();
+                return Err(e.into());
+            }
+        }
+        file.sync_all().await?;
+        fs::rename(&tmp, &path).await?;
+        Ok(())
+    }
+
+    /// There is nowhere to keep metadata, so the checksum is computed from the file
+    async fn head(&self, key: &str) -> Result<Option<ObjectMeta>> {
+        let content = match fs::read(self.path(key)).await {
+            Ok(content) => content,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        Ok(Some(ObjectMeta {
+            size: content.len() as u64,
+            sha256: Some(format!("{:x}", Sha256::digest(&content))),
+            storage_class: StorageClass::Standard,
+            archived: false,
+        }))
+    }
+
+    async fn exists(&self, key: &str) -> Result<bool> {
+        Ok(fs::metadata(self.path(key)).await.is_ok())
+    }
+
+    async fn get(&self, key: &str) -> Result<Bytes> {
+        Ok(fs::read(self.path(key)).await?.into())
+    }
+
+    fn list(&self) -> BoxStream<'_, Result<ObjectEntry>> {
+        let mut entries = vec![];
+        match walk(&self.root, &self.root, &mut entries) {
+            Ok(()) => stream::iter(entries.into_iter().map(Ok)).boxed(),
+            Err(e) => stream::once(async move { Err(e.into()) }).boxed(),
+        }
+    }
+}
+
+#[test]
+fn test_local_storage() {
+    use bytes::Bytes;
+
+    let root = std::env::temp_dir().join(format!("crates-io-cn-{}", std::process::id()));
+    let storage = LocalStorage::new(&root);
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    runtime.block_on(async {
+        let chunks = vec![Ok(Bytes::from_static(b"ab")), Ok(Bytes::from_static(b"c"))];
+        let content = Box::pin(futures::stream::iter(chunks));
+        assert!(!storage.exists("serde/1.0.0").await.unwrap());
+        let sha256 = "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad";
+        storage
+            .put("serde/1.0.0", content, Some(3), sha256)
+            .await
+            .unwrap();
+        assert!(storage.exists("serde/1.0.0").await.unwrap());
+        let meta = storage.head("serde/1.0.0").await.unwrap().unwrap();
+        assert_eq!(meta.size, 3);
+        assert_eq!(meta.sha256.as_deref(), Some(sha256));
+    });
+    assert_eq!(std::fs::read(root.join("serde/1.0.0")).unwrap(), b"abc");
+    std::fs::remove_dir_all(root).unwrap();
+}
@@ -0,0 +1,348 @@
+//! Backends every crate is replicated to
+//!
+//! `STORAGE_BACKENDS` selects them as a comma separated list of `upyun`, `obs` and `local`,
+//! by default every remote backend compiled in.
+//!
+//! Uploads to each backend can be limited with `STORAGE_{NAME}_MAX_CONCURRENT` uploads at
+//! a time and `STORAGE_{NAME}_MAX_BPS` bytes per second, e.g. `STORAGE_UPYUN_MAX_BPS`.
+//! Both are unlimited by default.
+use std::env;
+use std::io;
+use std::pin::Pin;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use async_trait::async_trait;
+use bytes::Bytes;
+use futures::stream::BoxStream;
+use futures::Stream;
+use thiserror::Error;
+use tokio::sync::Semaphore;
+
+use crate::env_or;
+use limit::{throttle, RateLimiter};
+
+mod limit;
+mod local;
+#[cfg(feature = "obs")]
+mod obs;
+#[cfg(feature = "upyun")]
+mod upyun;
+
+pub use local::LocalStorage;
+#[cfg(feature = "obs")]
+pub use obs::ObsStorage;
+#[cfg(feature = "upyun")]
+pub use upyun::UpyunStorage;
+
+pub type Result<T> = std::result::Result<T, StorageError>;
+
+/// Content to upload, consumed as it becomes available
+pub type ByteStream = Pin<Box<dyn Stream<Item = io::Result<Bytes>> + Send + Sync>>;
+
+#[derive(Debug, Error)]
+pub enum StorageError {
+    #[error("io error: {0}")]
+    Io(#[from] io::Error),
+    #[cfg(feature = "upyun")]
+    #[error("upyun error: {0}")]
+    Upyun(#[from] crate::upyun::error::Error),
+    #[cfg(feature = "obs")]
+    #[error("obs error: {0}")]
+    Obs(#[from] crate::simple_obs::error::ObsError),
+    #[error("invalid storage configuration: {0}")]
+    Config(String),
+    #[error("{0} is not supported by this backend")]
+    Unsupported(&'static str),
+    #[error("{key} is corrupted, expected {expected}, got {actual}")]
+    Checksum {
+        key: String,
+        expected: String,
+        actual: String,
+    },
+}
+
+impl StorageError {
+    /// Whether the same operation may succeed if tried again
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            StorageError::Io(e) => matches!(
+                e.kind(),
+                io::ErrorKind::Interrupted | io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock
+            ),
+            #[cfg(feature = "upyun")]
+            StorageError::Upyun(e) => e.is_retryable(),
+            #[cfg(feature = "obs")]
+            StorageError::Obs(e) => e.is_retryable(),
+            StorageError::Config(_) | StorageError::Unsupported(_) => false,
+            // most likely damaged in transit
+            StorageError::Checksum { .. } => true,
+        }
+    }
+}
+
+/// An object found by `Storage::list`
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ObjectEntry {
+    pub key: String,
+    pub size: u64,
+}
+
+/// What a backend reports about a stored object
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ObjectMeta {
+    pub size: u64,
+    /// The SHA-256 hex attached by `Storage::put`, `None` for objects stored without it
+    pub sha256: Option<String>,
+    pub storage_class: StorageClass,
+    /// In an archive class and not restored, so its content cannot be read
+    pub archived: bool,
+}
+
+/// How an object is stored, cheaper classes cost more to read
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum StorageClass {
+    Standard,
+    /// infrequent access, readable at once
+    Warm,
+    /// archive, must be restored before it can be read
+    Cold,
+}
+
+impl StorageClass {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            StorageClass::Standard => "standard",
+            StorageClass::Warm => "warm",
+            StorageClass::Cold => "cold",
+        }
+    }
+}
+
+impl FromStr for StorageClass {
+    type Err = StorageError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_lowercase().as_str() {
+            "standard" => Ok(StorageClass::Standard),
+            "warm" => Ok(StorageClass::Warm),
+            "cold" => Ok(StorageClass::Cold),
+            _ => Err(StorageError::Config(format!("unknown storage class {}", s))),
+        }
+    }
+}
+
+/// Metadata key the SHA-256 of an object is stored under
+pub const SHA256_META: &str = "sha256";
+
+#[async_trait]
+pub trait Storage: Send + Sync {
+    /// Name used in logs, metrics and `STORAGE_BACKENDS`
+    fn name(&self) -> &str;
+
+    /// Store `content` under `key`, `length` is the total size if known
+    ///
+    /// `sha256` is the expected hex digest of the content, kept as object metadata
+    /// where the backend supports it.
+    async fn put(
+        &self,
+        key: &str,
+        content: ByteStream,
+        length: Option<usize>,
+        sha256: &str,
+    ) -> Result<()>;
+
+    /// Size and checksum of `key`, `None` if it does not exist
+    async fn head(&self, key: &str) -> Result<Option<ObjectMeta>>;
+
+    async fn exists(&self, key: &str) -> Result<bool> {
+        Ok(self.head(key).await?.is_some())
+    }
+
+    /// The whole content of `key`
+    async fn get(&self, key: &str) -> Result<Bytes>;
+
+    /// Every object in the backend, fetched lazily page by page
+    fn list(&self) -> BoxStream<'_, Result<ObjectEntry>>;
+
+    /// A download url for `key` valid for `ttl`, `None` if the backend cannot sign urls
+    ///
+    /// Does not check that `key` exists.
+    async fn signed_url(&self, _key: &str, _ttl: Duration) -> Result<Option<String>> {
+        Ok(None)
+    }
+
+    /// Move `key` to `class`, backends with a single class report `Unsupported`
+    async fn set_storage_class(&self, _key: &str, _class: StorageClass) -> Result<()> {
+        Err(StorageError::Unsupported("storage class"))
+    }
+
+    /// Make an archived `key` readable for `days`, it may take minutes to complete
+    async fn restore(&self, _key: &str, _days: u32) -> Result<()> {
+        Err(StorageError::Unsupported("restore"))
+    }
+
+    /// Url of `key` on the CDN in front of this backend, `None` if there is none
+    fn cdn_url(&self, _key: &str) -> Option<String> {
+        None
+    }
+
+    /// Drop `urls` of objects in this backend from its CDN cache, so a re-uploaded object
+    /// is not served stale, backends without a CDN report `Unsupported`
+    async fn purge(&self, _urls: &[String]) -> Result<()> {
+        Err(StorageError::Unsupported("purge"))
+    }
+
+    /// Background upkeep such as refreshing credentials, spawned once at startup
+    async fn maintain(&self) {}
+
+    /// Backend specific lines in Prometheus text format
+    async fn metrics(&self) -> String {
+        String::new()
+    }
+}
+
+/// Build the backend called `name` from its environment variables
+pub fn from_env(name: &str) -> Result<Box<dyn Storage>> {
+    match name {
+        "local" => Ok(Box::new(LocalStorage::from_env()?)),
+        #[cfg(feature = "upyun")]
+        "upyun" => Ok(Box::new(UpyunStorage::from_env()?)),
+        #[cfg(feature = "obs")]
+        "obs" => Ok(Box::new(ObsStorage::from_env()?)),
+        _ => Err(StorageError::Config(format!(
+            "unknown storage backend {}, or its feature is not enabled",
+            name
+        ))),
+    }
+}
+
+/// Every remote backend compiled in
+const DEFAULT_BACKENDS: &[&str] = &[
+    #[cfg(feature = "upyun")]
+    "upyun",
+    #[cfg(feature = "obs")]
+    "obs",
+];
+
+/// Backend names from `STORAGE_BACKENDS`
+pub fn backends_from_env() -> Vec<String> {
+    match env::var("STORAGE_BACKENDS") {
+        Ok(names) => names
+            .split(',')
+            .map(str::trim)
+            .filter(|name| !name.is_empty())
+            .map(str::to_string)
+            .collect(),
+        Err(_) => DEFAULT_BACKENDS
+            .iter()
+            .map(|name| name.to_string())
+            .collect(),
+    }
+}
+
+fn required(key: &str) -> Result<String> {
+    env::var(key).map_err(|_| StorageError::Config(format!("{} is not set", key)))
+}
+
+/// Attempts at each page of a listing, a page that fails for good ends it
+#[cfg(any(feature = "upyun", feature = "obs"))]
+const LIST_ATTEMPTS: u32 = 5;
+
+/// `list_page` retried with backoff while it fails transiently, a single timeout would
+/// otherwise force a migration of millions of keys to list again from the start
+#[cfg(any(feature = "upyun", feature = "obs"))]
+async fn retry_page<T, F, Fut>(mut list_page: F) -> Result<T>
+where
+    F: FnMut() -> Fut,
+    Fut: std::future::Future<Output = Result<T>>,
+{
+    let mut attempt = 1;
+    loop {
+        match list_page().await {
+            Err(e) if e.is_retryable() && attempt < LIST_ATTEMPTS => {
+                let delay = crate::retry::backoff(attempt);
+                warn!(
+                    "listing page attempt {} failed: {}, retry in {:?}",
+                    attempt, e, delay
+                );
+                attempt += 1;
+                tokio::time::sleep(delay).await;
+            }
+            result => return result,
+        }
+    }
+}
+
+/// A configured backend, the limits on uploads to it and their outcome
+pub struct Replica {
+    pub storage: Box<dyn Storage>,
+    /// permits for concurrent uploads, `None` if unlimited
+    uploads: Option<Semaphore>,
+    bandwidth: Option<Arc<RateLimiter>>,
+    uploaded: AtomicUsize,
+    failed: AtomicUsize,
+}
+
+impl Replica {
+    /// Limits are read from `STORAGE_{NAME}_MAX_CONCURRENT` and `STORAGE_{NAME}_MAX_BPS`
+    pub fn new(storage: Box<dyn Storage>) -> Self {
+        let prefix = format!("STORAGE_{}", storage.name().to_uppercase());
+        let max_concurrent: usize = env_or(&format!("{}_MAX_CONCURRENT", prefix), 0);
+        let max_bps: u64 = env_or(&format!("{}_MAX_BPS", prefix), 0);
+        Replica {
+            storage,
+            uploads: Some(max_concurrent).filter(|&n| n > 0).map(Semaphore::new),
+            bandwidth: Some(max_bps)
+                .filter(|&bps| bps > 0)
+                .map(|bps| Arc::new(RateLimiter::new(bps))),
+            uploaded: AtomicUsize::new(0),
+            failed: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        self.storage.name()
+    }
+
+    /// `Storage::put` within the limits of this backend, waits for a free upload slot
+    pub async fn put(
+        &self,
+        key: &str,
+        content: ByteStream,
+        length: Option<usize>,
+        sha256: &str,
+    ) -> Result<()> {
+        let _permit = match self.uploads {
+            Some(ref uploads) => Some(uploads.acquire().await.expect("never closed")),
+            None => None,
+        };
+        let content = match self.bandwidth {
+            Some(ref bandwidth) => throttle(content, bandwidth.clone()),
+            None => content,
+        };
+        self.storage.put(key, content, length, sha256).await
+    }
+
+    pub fn record(&self, uploaded: bool) {
+        let counter = if uploaded {
+            &self.uploaded
+        } else {
+            &self.failed
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub async fn metrics(&self) -> String {
+        format!(
+            "storage_uploads_total{{backend=\"{name}\",result=\"ok\"}} {}\n\
+             storage_uploads_total{{backend=\"{name}\",result=\"failed\"}} {}\n{}",
+            self.uploaded.load(Ordering::Relaxed),
+            self.failed.load(Ordering::Relaxed),
+            self.storage.metrics().await,
+            name = self.name()
+        )
+    }
+}
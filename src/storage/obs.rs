@@ -0,0 +1,189 @@
+use std::collections::HashMap;
+use std::time::Duration;
+
+use async_trait::async_trait;
+use bytes::Bytes;
+use futures::stream::{self, BoxStream};
+use futures::StreamExt;
+
+use super::{
+    required, retry_page, ByteStream, ObjectEntry, ObjectMeta, Result, Storage, StorageClass,
+    SHA256_META,
+};
+use crate::env_or;
+use crate::simple_obs::error::ObsError;
+use crate::simple_obs::{
+    AutoRefreshingProvider, Bucket, ChainProvider, ProvideObsCredentials, Ssl,
+};
+
+const LIST_LIMIT: usize = 1000;
+
+/// A Huawei Cloud OBS bucket
+///
+/// - `OBS_BUCKET_NAME`, `OBS_ENDPOINT`: bucket and its region endpoint
+/// - `OBS_SSL`: `false` to talk plain http, e.g. to a mock endpoint
+///
+/// Credentials come from `ChainProvider`.
+pub struct ObsStorage {
+    bucket: Bucket,
+    credentials: AutoRefreshingProvider<ChainProvider>,
+}
+
+impl ObsStorage {
+    pub fn from_env() -> Result<Self> {
+        let ssl = if env_or("OBS_SSL", true) {
+            Ssl::Yes
+        } else {
+            Ssl::No
+        };
+        let mut bucket = Bucket::new(
+            &required("OBS_BUCKET_NAME")?,
+            &required("OBS_ENDPOINT")?,
+            ssl,
+        );
+        bucket.set_multipart_threshold(env_or("OBS_MULTIPART_THRESHOLD", 8 * 1024 * 1024));
+        Ok(ObsStorage {
+            bucket,
+            credentials: AutoRefreshingProvider::new(ChainProvider::new()),
+        })
+    }
+}
+
+#[async_trait]
+impl Storage for ObsStorage {
+    fn name(&self) -> &str {
+        "obs"
+    }
+
+    async fn put(
+        &self,
+        key: &str,
+        content: ByteStream,
+        length: Option<usize>,
+        sha256: &str,
+    ) -> Result<()> {
+        let credentials = self.credentials.credentials().await?;
+        let mut meta = HashMap::new();
+        meta.insert(SHA256_META.to_string(), sha256.to_string());
+        self.bucket
+            .put_stream(key, content, length, &meta, &credentials)
+            .await?;
+        Ok(())
+    }
+
+    async fn head(&self, key: &str) -> Result<Option<ObjectMeta>> {
+        let credentials = self.credentials.credentials().await?;
+        let info = self.bucket.head(key, &credentials).await?;
+        Ok(info.map(|mut info| {
+            let storage_class = match info.storage_class.as_deref() {
+                Some("WARM") => StorageClass::Warm,
+                Some("COLD") => StorageClass::Cold,
+                _ => StorageClass::Standard,
+            };
+            let restored = matches!(
+                info.restore.as_deref(),
+                Some(restore) if restore.contains("ongoing-request=\"false\"")
+            );
+            ObjectMeta {
+                size: info.size,
+                sha256: info.meta.remove(SHA256_META),
+                storage_class,
+                archived: storage_class == StorageClass::Cold && !restored,
+            }
+        }))
+    }
+
+    async fn set_storage_class(&self, key: &str, class: StorageClass) -> Result<()> {
+        let credentials = self.credentials.credentials().await?;
+        let class = match class {
+            StorageClass::Standard => "STANDARD",
+            StorageClass::Warm => "WARM",
+            StorageClass::Cold => "COLD",
+        };
+        self.bucket
+            .set_storage_class(key, class, &credentials)
+            .await?;
+        Ok(())
+    }
+
+    /// Expedited, readable within minutes, a restore already in progress is not an error
+    async fn restore(&self, key: &str, days: u32) -> Result<()> {
+        let credentials = self.credentials.credentials().await?;
+        match self
+            .bucket
+            .restore(key, days, "Expedited", &credentials)
+            .await
+        {
+            Err(ObsError::Service { ref code, .. }) if code == "RestoreAlreadyInProgress" => Ok(()),
+            result => Ok(result?),
+        }
+    }
+
+    async fn get(&self, key: &str) -> Result<Bytes> {
+        let credentials = self.credentials.credentials().await?;
+        let resp = self.bucket.get(key, &credentials).await?;
+        Ok(resp.bytes().await.map_err(ObsError::from)?)
+    }
+
+    fn list(&self) -> BoxStream<'_, Result<ObjectEntry>> {
+        // next marker, `None` once the last page is fetched, and objects not yet yielded
+        let state = (Some(None), Vec::new().into_iter());
+        stream::unfold(state, move |(mut marker, mut found)| async move {
+            loop {
+                if let Some(object) = found.next() {
+                    return Some((Ok(object), (marker, found)));
+                }
+                let current: Option<String> = marker.take()?;
+                let page = retry_page(|| async {
+                    let credentials = self.credentials.credentials().await?;
+                    let page = self
+                        .bucket
+                        .list("", current.as_deref(), LIST_LIMIT, &credentials)
+                        .await?;
+                    Ok(page)
+                })
+                .await;
+                let page = match page {
+                    Ok(page) => page,
+                    Err(e) => return Some((Err(e), (None, found))),
+                };
+                if page.is_truncated {
+                    marker = Some(page.next_marker);
+                }
+                found = page
+                    .contents
+                    .into_iter()
+                    .map(|object| ObjectEntry {
+                        key: object.key,
+                        size: object.size,
+                    })
+                    .collect::<Vec<_>>()
+                    .into_iter();
+            }
+        })
+        .boxed()
+    }
+
+    async fn signed_url(&self, key: &str, ttl: Duration) -> Result<Option<String>> {
+        let credentials = self.credentials.credentials().await?;
+        Ok(Some(self.bucket.presigned_url(key, ttl, &credentials)))
+    }
+
+    /// Keep credentials fresh in the background
+    async fn maintain(&self) {
+        self.credentials.refresh_loop().await
+    }
+
+    async fn metrics(&self) -> String {
+        let stats = self.credentials.stats().await;
+        let seconds = |d: Option<chrono::Duration>| d.map_or(-1, |d| d.num_seconds());
+        format!(
+            "# TYPE obs_credentials_age_seconds gauge\nobs_credentials_age_seconds {}\n\
+             # TYPE obs_credentials_expires_in_seconds gauge\nobs_credentials_expires_in_seconds {}\n\
+             # TYPE obs_credentials_refresh_failures gauge\nobs_credentials_refresh_failures {}\n",
+            seconds(stats.age),
+            seconds(stats.expires_in),
+            stats.failures
+        )
+    }
+}
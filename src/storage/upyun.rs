@@ -0,0 +1,195 @@
+use std::collections::{HashMap, VecDeque};
+use std::time::Duration;
+
+use async_trait::async_trait;
+use bytes::Bytes;
+use futures::stream::{self, BoxStream};
+use futures::{StreamExt, TryStreamExt};
+
+use super::{
+    required, retry_page, ByteStream, ObjectEntry, ObjectMeta, Result, Storage, StorageClass,
+    SHA256_META,
+};
+use crate::env_or;
+use crate::upyun::{token_url, FetchResult, FetchTask, Operator, Upyun, MAX_PURGE_URLS};
+
+const LIST_LIMIT: usize = 1000;
+
+/// An Upyun bucket
+///
+/// - `UPYUN_NAME`, `UPYUN_TOKEN`: operator and its password
+/// - `UPYUN_BUCKET`: service name
+/// - `UPYUN_DOMAIN`: CDN domain, for signed urls and purging
+/// - `UPYUN_UPT_SECRET`: token anti-leech secret of the CDN domain, for signed urls
+pub struct UpyunStorage {
+    upyun: Upyun,
+    bucket: String,
+    domain: Option<String>,
+    upt_secret: Option<String>,
+    probe_interval: Duration,
+}
+
+impl UpyunStorage {
+    pub fn from_env() -> Result<Self> {
+        let leak = |value: String| -> &'static str { Box::leak(value.into_boxed_str()) };
+        let operator = Operator::new(
+            leak(required("UPYUN_NAME")?),
+            leak(required("UPYUN_TOKEN")?),
+        );
+        let mut upyun = Upyun::new(operator);
+        upyun.set_multipart_threshold(env_or("UPYUN_MULTIPART_THRESHOLD", 8 * 1024 * 1024));
+        Ok(UpyunStorage {
+            upyun,
+            bucket: required("UPYUN_BUCKET")?,
+            domain: required("UPYUN_DOMAIN").ok(),
+            upt_secret: required("UPYUN_UPT_SECRET").ok(),
+            probe_interval: Duration::from_secs(env_or("UPYUN_PROBE_INTERVAL", 600)),
+        })
+    }
+
+    /// Have Upyun download each url into its key, up to `MAX_FETCH_TASKS` at once,
+    /// returns the task ids
+    pub async fn fetch(
+        &self,
+        tasks: &[FetchTask],
+        notify_url: Option<&str>,
+    ) -> Result<Vec<String>> {
+        Ok(self
+            .upyun
+            .submit_fetch(&self.bucket, tasks, notify_url)
+            .await?)
+    }
+
+    /// Tag an object stored by other means than `put` with its SHA-256, as `put` does
+    pub async fn set_sha256(&self, key: &str, sha256: &str) -> Result<()> {
+        let mut meta = HashMap::new();
+        meta.insert(SHA256_META.to_string(), sha256.to_string());
+        self.upyun.update_meta(&self.bucket, key, &meta).await?;
+        Ok(())
+    }
+
+    /// Results of fetch tasks, `None` for those still running
+    pub async fn fetch_results(
+        &self,
+        ids: &[String],
+    ) -> Result<HashMap<String, Option<FetchResult>>> {
+        Ok(self.upyun.fetch_results(&self.bucket, ids).await?)
+    }
+}
+
+#[async_trait]
+impl Storage for UpyunStorage {
+    fn name(&self) -> &str {
+        "upyun"
+    }
+
+    async fn put(
+        &self,
+        key: &str,
+        content: ByteStream,
+        length: Option<usize>,
+        sha256: &str,
+    ) -> Result<()> {
+        let mut meta = HashMap::new();
+        meta.insert(SHA256_META.to_string(), sha256.to_string());
+        self.upyun
+            .put_stream(&self.bucket, key, content, length, &meta)
+            .await?;
+        Ok(())
+    }
+
+    async fn head(&self, key: &str) -> Result<Option<ObjectMeta>> {
+        let info = self.upyun.head_file(&self.bucket, key).await?;
+        Ok(info.map(|mut info| ObjectMeta {
+            size: info.size,
+            sha256: info.meta.remove(SHA256_META),
+            storage_class: StorageClass::Standard,
+            archived: false,
+        }))
+    }
+
+    async fn get(&self, key: &str) -> Result<Bytes> {
+        let stream = self.upyun.get_file(&self.bucket, key).await?;
+        let chunks: Vec<Bytes> = stream.try_collect().await?;
+        Ok(chunks.concat().into())
+    }
+
+    /// Walks folders depth first, listing is per folder in Upyun
+    fn list(&self) -> BoxStream<'_, Result<ObjectEntry>> {
+        // folders still to list with their continuation, and files found but not yet yielded
+        let state = (vec![(String::new(), None)], VecDeque::new());
+        stream::unfold(state, move |(mut folders, mut found)| async move {
+            loop {
+                if let Some(entry) = found.pop_front() {
+                    return Some((Ok(entry), (folders, found)));
+                }
+                let (dir, iter): (String, Option<String>) = folders.pop()?;
+                let page = retry_page(|| async {
+                    let page = self
+                        .upyun
+                        .list_dir(&self.bucket, &dir, iter.as_deref(), LIST_LIMIT)
+                        .await?;
+                    Ok(page)
+                })
+                .await;
+                let page = match page {
+                    Ok(page) => page,
+                    Err(e) => return Some((Err(e), (vec![], found))),
+                };
+                if page.iter.is_some() {
+                    folders.push((dir.clone(), page.iter));
+                }
+                for file in page.files {
+                    let key = if dir.is_empty() {
+                        file.name.clone()
+                    } else {
+                        format!("{}/{}", dir, file.name)
+                    };
+                    if file.is_folder() {
+                        folders.push((key, None));
+                    } else {
+                        found.push_back(ObjectEntry {
+                            key,
+                            size: file.length,
+                        });
+                    }
+                }
+            }
+        })
+        .boxed()
+    }
+
+    async fn signed_url(&self, key: &str, ttl: Duration) -> Result<Option<String>> {
+        let (domain, secret) = match (&self.domain, &self.upt_secret) {
+            (Some(domain), Some(secret)) => (domain, secret),
+            _ => return Ok(None),
+        };
+        let ttl = chrono::Duration::from_std(ttl).unwrap_or_else(|_| chrono::Duration::zero());
+        Ok(Some(token_url(domain, secret, &format!("/{}", key), ttl)))
+    }
+
+    fn cdn_url(&self, key: &str) -> Option<String> {
+        self.domain
+            .as_ref()
+            .map(|domain| format!("https://{}/{}", domain, key))
+    }
+
+    /// Urls not on a domain bound to the bucket are logged and skipped
+    async fn purge(&self, urls: &[String]) -> Result<()> {
+        for batch in urls.chunks(MAX_PURGE_URLS) {
+            let invalid = self.upyun.purge(&self.bucket, batch).await?;
+            if !invalid.is_empty() {
+                warn!("{:?} are not served from {}", invalid, self.bucket);
+            }
+        }
+        Ok(())
+    }
+
+    /// Pick the fastest endpoint now and every `UPYUN_PROBE_INTERVAL` seconds
+    async fn maintain(&self) {
+        loop {
+            self.upyun.select_provider().await;
+            tokio::time::sleep(self.probe_interval).await;
+        }
+    }
+}
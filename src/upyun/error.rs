@@ -1,6 +1,8 @@
 use derive_more::{Display, Error, From};
 use phf::phf_map;
 
+use crate::retry;
+
 pub type Result<T> = std::result::Result<T, Error>;
 
 #[derive(Error, From)]
@@ -12,7 +14,45 @@ pub struct UpyunError {
 pub enum Error {
     Reqwest(reqwest::Error),
     SerdeJSON(serde_json::Error),
+    /// reading the content to upload failed
+    #[display(fmt = "read content: {}", _0)]
+    Io(std::io::Error),
     Upyun(UpyunError),
+    #[display(fmt = "missing header {}", _0)]
+    MissingHeader(#[error(not(source))] &'static str),
+    /// error response without an Upyun error code
+    #[display(fmt = "unexpected status {}", _0)]
+    Status(#[error(not(source))] reqwest::StatusCode),
+}
+
+impl Error {
+    /// Transient transport failures and statuses, or an Upyun code marked transient
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            Error::Reqwest(e) => retry::is_transient(e),
+            Error::SerdeJSON(_) | Error::Io(_) | Error::MissingHeader(_) => false,
+            Error::Upyun(e) => e.is_retryable(),
+            Error::Status(status) => retry::is_transient_status(*status),
+        }
+    }
+}
+
+impl UpyunError {
+    pub fn code(&self) -> u64 {
+        self.code
+    }
+
+    /// Timeouts, rate limits, server side failures, clock skew and corruption
+    /// in transit are transient, everything else is permanent
+    pub fn is_retryable(&self) -> bool {
+        match self.code / 100_000 {
+            408 | 429 | 444 | 503 | 599 => true,
+            _ => matches!(
+                self.code,
+                0 | 40001001 | 40000006 | 40300007 | 40100002 | 40100016 | 40300028
+            ),
+        }
+    }
 }
 
 impl std::fmt::Debug for UpyunError {
@@ -0,0 +1,115 @@
+//! Asynchronous fetch tasks, Upyun downloads a url into the bucket by itself
+//!
+//! https://help.upyun.com/knowledge-base/async_fetch/
+use std::collections::HashMap;
+
+use reqwest::Method;
+use serde::{Deserialize, Serialize};
+
+use super::error::Result;
+use super::Upyun;
+
+const PROCESS_API: &str = "https://p0.api.upyun.com";
+
+/// Most tasks submitted or queried in one request
+pub const MAX_FETCH_TASKS: usize = 20;
+
+/// Download `url` and store it as `save_as`, an absolute path in the bucket
+#[derive(Debug, Clone, Serialize)]
+pub struct FetchTask {
+    pub url: String,
+    pub save_as: String,
+    pub random: bool,
+    pub overwrite: bool,
+}
+
+impl FetchTask {
+    pub fn new<U: Into<String>>(url: U, key: &str) -> Self {
+        FetchTask {
+            url: url.into(),
+            save_as: format!("/{}", key),
+            random: false,
+            overwrite: true,
+        }
+    }
+}
+
+/// Outcome of a finished task, `status_code` is the one upstream responded
+#[derive(Debug, Clone, Deserialize)]
+pub struct FetchResult {
+    pub status_code: u16,
+    #[serde(default)]
+    pub description: String,
+    #[serde(default)]
+    pub path: Vec<String>,
+}
+
+impl FetchResult {
+    pub fn is_success(&self) -> bool {
+        self.status_code == 200
+    }
+}
+
+#[derive(Deserialize)]
+struct ResultResponse {
+    tasks: HashMap<String, Option<FetchResult>>,
+}
+
+impl Upyun {
+    /// Submit up to `MAX_FETCH_TASKS` tasks, returns their ids in the same order
+    ///
+    /// Upyun posts each result to `notify_url` if given, they can be polled with
+    /// `fetch_results` either way.
+    pub async fn submit_fetch<B>(
+        &self,
+        bucket: B,
+        tasks: &[FetchTask],
+        notify_url: Option<&str>,
+    ) -> Result<Vec<String>>
+    where
+        B: AsRef<str>,
+    {
+        let tasks = base64::encode(serde_json::to_string(tasks)?);
+        let mut form = vec![
+            ("service", bucket.as_ref()),
+            ("app_name", "spiderman"),
+            ("tasks", &tasks),
+        ];
+        if let Some(notify_url) = notify_url {
+            form.push(("notify_url", notify_url));
+        }
+        let req = self
+            .operator
+            .request_at(Method::POST, PROCESS_API, "/pretreatment/", None, None)
+            .form(&form);
+        let resp = self.check(req.send().await?).await?;
+        Ok(resp.json().await?)
+    }
+
+    /// Results of up to `MAX_FETCH_TASKS` tasks, `None` for those still running
+    pub async fn fetch_results<B>(
+        &self,
+        bucket: B,
+        ids: &[String],
+    ) -> Result<HashMap<String, Option<FetchResult>>>
+    where
+        B: AsRef<str>,
+    {
+        let task_ids = ids.join(",");
+        let req = self
+            .operator
+            .request_at(Method::GET, PROCESS_API, "/result/", None, None)
+            .query(&[("service", bucket.as_ref()), ("task_ids", &task_ids)]);
+        let resp = self.check(req.send().await?).await?;
+        let ResultResponse { tasks } = resp.json().await?;
+        Ok(tasks)
+    }
+}
+
+#[test]
+fn test_fetch_result() {
+    let body = r#"{"tasks":{"a":{"status_code":200,"path":["/serde/1.0.0"],"description":"OK"},"b":null}}"#;
+    let ResultResponse { tasks } = serde_json::from_str(body).unwrap();
+    assert!(tasks["a"].as_ref().unwrap().is_success());
+    assert!(tasks["b"].is_none());
+}
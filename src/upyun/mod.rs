@@ -1,15 +1,34 @@
 #![allow(dead_code)]
+use std::collections::HashMap;
+use std::io;
+use std::sync::atomic::{AtomicI64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Instant;
+
 use base64::encode as b64enc;
-use chrono::{DateTime, Utc};
+use chrono::{DateTime, Duration, NaiveDateTime, Utc};
+use hmac::{Hmac, Mac, NewMac};
+use md5::{Digest, Md5};
+use sha1::Sha1;
 
 use bytes::Bytes;
-use reqwest::{header, Method, RequestBuilder, StatusCode};
+use futures::future::join_all;
+use futures::{stream, Stream, StreamExt, TryStreamExt};
+use reqwest::{header, Body, Method, RequestBuilder, Response};
+use serde::Deserialize;
 use serde_json::Value;
 
 pub mod error;
+mod fetch;
 mod provider;
+mod purge;
 use error::{Error, Result, UpyunError};
+pub use fetch::{FetchResult, FetchTask, MAX_FETCH_TASKS};
 pub use provider::Provider;
+pub use purge::MAX_PURGE_URLS;
+
+use crate::parts::{parts, peek_whole, Content};
+use crate::retry;
 
 lazy_static! {
     static ref CLIENT: reqwest::Client = reqwest::Client::new();
@@ -18,26 +37,118 @@ lazy_static! {
 #[derive(Debug, Clone)]
 pub struct Upyun {
     operator: Operator,
-    provider: Provider,
+    /// index of the current endpoint in `Provider::ALL`
+    provider: Arc<AtomicUsize>,
+    /// consecutive failed requests through the current endpoint
+    failures: Arc<AtomicUsize>,
+    multipart_threshold: usize,
+}
+
+/// Metadata returned by `HEAD` on a file or folder
+#[derive(Debug, Clone)]
+pub struct FileInfo {
+    /// `file` or `folder`
+    pub file_type: String,
+    pub size: u64,
+    pub date: Option<DateTime<Utc>>,
+    pub content_type: Option<String>,
+    pub content_md5: Option<String>,
+    /// `x-upyun-meta-*` headers, keyed without the prefix
+    pub meta: HashMap<String, String>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct ListEntry {
+    pub name: String,
+    /// `folder`, or the content type of a file
+    #[serde(rename = "type")]
+    pub file_type: String,
+    pub length: u64,
+    pub last_modified: i64,
+}
+
+impl ListEntry {
+    pub fn is_folder(&self) -> bool {
+        self.file_type == "folder"
+    }
 }
 
+/// One page of a directory listing, `iter` is `None` on the last page
+#[derive(Debug, Clone)]
+pub struct ListPage {
+    pub files: Vec<ListEntry>,
+    pub iter: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct ListResponse {
+    files: Vec<ListEntry>,
+    iter: Option<String>,
+}
+
+/// Switch to another endpoint after this many consecutive failures
+const FAILOVER_THRESHOLD: usize = 3;
+const PROBE_ROUNDS: usize = 3;
+const PROBE_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(5);
+
+/// Marks there is no more page to list
+const LIST_EOF: &str = "g2gCZAAEbmV4dGQAA2VvZg";
+const META_PREFIX: &str = "x-upyun-meta-";
+
+/// Every part but the last must be exactly 1 MiB
+const PART_SIZE: usize = 1024 * 1024;
+const PART_ATTEMPTS: u32 = 3;
+const PART_CONCURRENCY: usize = 4;
+/// `part already complete`, a retried part which did arrive the first time
+const PART_COMPLETED: u64 = 40011062;
+
+/// Clock differences below this are within the precision of the `Date` header
+const MAX_SKEW_DRIFT: i64 = 5;
+
 #[derive(Debug, Clone)]
 pub struct Operator {
     name: &'static str,
-    passwd: &'static str,
-    authorization: String,
+    /// md5 hex of the password, which is the HMAC key, the password itself is not kept
+    secret: String,
+    /// seconds the Upyun clock is ahead of ours, learnt from response `Date`
+    skew: Arc<AtomicI64>,
 }
 
 impl Operator {
     pub fn new(name: &'static str, passwd: &'static str) -> Self {
-        let authorization = format!("Basic {}", b64enc(format!("{}:{}", name, passwd)));
         Self {
             name,
-            passwd,
-            authorization,
+            secret: md5_hex(passwd),
+            skew: Arc::new(AtomicI64::new(0)),
         }
     }
 
+    /// https://help.upyun.com/knowledge-base/object_storage_authorization/#e7adbee5908de8aea1e7ae97
+    ///
+    /// ```text
+    /// Signature = Base64(HMAC-SHA1(MD5(Password), Method&URI&Date[&Policy][&Content-MD5]))
+    /// Authorization: UPYUN Operator:Signature
+    /// ```
+    pub fn sign(
+        &self,
+        method: &Method,
+        uri: &str,
+        date: &str,
+        policy: Option<&str>,
+        content_md5: Option<&str>,
+    ) -> String {
+        let mut string = format!("{}&{}&{}", method, uri, date);
+        for part in policy.iter().chain(content_md5.iter()) {
+            string.push('&');
+            string.push_str(part);
+        }
+        let mut hmac = Hmac::<Sha1>::new_varkey(self.secret.as_bytes())
+            .expect("HMAC can take key of any size");
+        hmac.update(string.as_bytes());
+        let signature = b64enc(hmac.finalize().into_bytes());
+        format!("UPYUN {}:{}", self.name, signature)
+    }
+
     pub fn request<P>(
         &self,
         method: Method,
@@ -48,16 +159,69 @@ impl Operator {
     where
         P: AsRef<str>,
     {
-        let url = format!("{}{}", provider.as_ref(), path.as_ref());
+        self.signed_request(method, provider, path, date, None)
+    }
+
+    /// Like `request`, with `Content-MD5` sent and covered by the signature
+    pub fn signed_request<P>(
+        &self,
+        method: Method,
+        provider: Provider,
+        path: P,
+        date: Option<DateTime<Utc>>,
+        content_md5: Option<&str>,
+    ) -> RequestBuilder
+    where
+        P: AsRef<str>,
+    {
+        self.request_at(method, provider.as_ref(), path, date, content_md5)
+    }
+
+    /// A signed request to `path` on `base`, for the APIs outside the storage endpoints
+    pub fn request_at<P>(
+        &self,
+        method: Method,
+        base: &str,
+        path: P,
+        date: Option<DateTime<Utc>>,
+        content_md5: Option<&str>,
+    ) -> RequestBuilder
+    where
+        P: AsRef<str>,
+    {
+        let url = format!("{}{}", base, path.as_ref());
         debug!("{}", url);
+        let date = format_gmt(date.unwrap_or_else(|| self.now()));
+        let authorization = self.sign(&method, path.as_ref(), &date, None, content_md5);
         let req = CLIENT
             .request(method, &url)
             .header(header::USER_AGENT, "upyun-client (crates-io.cn)")
-            .header(header::AUTHORIZATION, &self.authorization);
+            .header(header::AUTHORIZATION, authorization)
+            .header(header::DATE, date);
+        match content_md5 {
+            Some(md5) => req.header("content-md5", md5),
+            None => req,
+        }
+    }
+
+    /// Local time corrected by the learnt clock skew
+    pub fn now(&self) -> DateTime<Utc> {
+        Utc::now() + Duration::seconds(self.skew.load(Ordering::Relaxed))
+    }
+
+    /// Learn the clock skew from the `Date` of a response, so that requests
+    /// keep within the offset Upyun accepts even if the local clock drifts
+    fn observe_date(&self, headers: &header::HeaderMap) {
+        let date = headers
+            .get(header::DATE)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| DateTime::parse_from_rfc2822(v).ok());
         if let Some(date) = date {
-            req.header(header::DATE, format_gmt(date))
-        } else {
-            req.header(header::DATE, format_gmt(Utc::now()))
+            let skew = (date.with_timezone(&Utc) - Utc::now()).num_seconds();
+            let previous = self.skew.swap(skew, Ordering::Relaxed);
+            if (skew - previous).abs() > MAX_SKEW_DRIFT {
+                warn!("upyun clock skew changed from {}s to {}s", previous, skew);
+            }
         }
     }
 }
@@ -66,38 +230,470 @@ impl Upyun {
     pub fn new(operator: Operator) -> Self {
         Self {
             operator,
-            provider: Provider::Auto,
+            provider: Arc::new(AtomicUsize::new(Provider::Auto.index())),
+            failures: Arc::new(AtomicUsize::new(0)),
+            multipart_threshold: usize::MAX,
+        }
+    }
+
+    /// Turn a non-2xx response into an `UpyunError`
+    ///
+    /// The code comes from `x-error-code`, falling back to the JSON body for
+    /// responses that carry one.
+    async fn check(&self, resp: Response) -> Result<Response> {
+        self.operator.observe_date(resp.headers());
+        if resp.status().is_success() {
+            return Ok(resp);
         }
+        let code = resp
+            .headers()
+            .get("x-error-code")
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.parse().ok());
+        let status = resp.status();
+        let code = match code {
+            Some(code) => Some(code),
+            None => resp
+                .json::<Value>()
+                .await
+                .ok()
+                .and_then(|err| err["code"].as_u64()),
+        };
+        match code {
+            Some(code) => Err(Error::Upyun(UpyunError::from(code))),
+            None => Err(Error::Status(status)),
+        }
+    }
+
+    pub fn provider(&self) -> Provider {
+        Provider::ALL[self.provider.load(Ordering::Relaxed)]
     }
 
-    pub fn set_provider(&mut self, provider: Provider) {
-        self.provider = provider;
+    pub fn set_provider(&self, provider: Provider) {
+        self.provider.store(provider.index(), Ordering::Relaxed);
+        self.failures.store(0, Ordering::Relaxed);
+    }
+
+    /// Probe the latency of every endpoint and switch to the fastest reachable one
+    pub async fn select_provider(&self) -> Provider {
+        let probes = Provider::ALL
+            .iter()
+            .map(|provider| async move { (*provider, self.probe(*provider).await) });
+        let fastest = join_all(probes)
+            .await
+            .into_iter()
+            .filter_map(|(provider, rtt)| rtt.map(|rtt| (rtt, provider)))
+            .min_by_key(|(rtt, _)| *rtt);
+        match fastest {
+            Some((rtt, provider)) => {
+                info!("upyun endpoint {:?} selected, rtt {:?}", provider, rtt);
+                self.set_provider(provider);
+            }
+            None => warn!("no upyun endpoint reachable, keep {:?}", self.provider()),
+        }
+        self.provider()
+    }
+
+    /// Best round trip time of `provider`, `None` if it is unreachable
+    async fn probe(&self, provider: Provider) -> Option<std::time::Duration> {
+        let mut best = None;
+        for _ in 0..PROBE_ROUNDS {
+            let start = Instant::now();
+            match CLIENT
+                .head(provider.as_ref())
+                .timeout(PROBE_TIMEOUT)
+                .send()
+                .await
+            {
+                Ok(_) => {
+                    let rtt = start.elapsed();
+                    best = Some(best.map_or(rtt, |best: std::time::Duration| best.min(rtt)));
+                }
+                Err(e) => debug!("probe {:?} failed: {}", provider, e),
+            }
+        }
+        best
+    }
+
+    /// Send `req`, failing over to the next endpoint when the current one
+    /// keeps erroring
+    async fn send(&self, req: RequestBuilder) -> Result<Response> {
+        let result = req.send().await;
+        let failed = match &result {
+            Ok(resp) => resp.status().is_server_error(),
+            Err(_) => true,
+        };
+        if !failed {
+            self.failures.store(0, Ordering::Relaxed);
+        } else if self.failures.fetch_add(1, Ordering::Relaxed) + 1 >= FAILOVER_THRESHOLD {
+            let current = self.provider();
+            let next = Provider::ALL[(current.index() + 1) % Provider::ALL.len()];
+            warn!(
+                "upyun endpoint {:?} keeps failing, switch to {:?}",
+                current, next
+            );
+            self.set_provider(next);
+        }
+        Ok(result?)
+    }
+
+    /// Files larger than `threshold` bytes are sent with the multipart protocol
+    pub fn set_multipart_threshold(&mut self, threshold: usize) {
+        self.multipart_threshold = threshold;
     }
 
     pub async fn put_file<B, K>(&self, bucket: B, key: K, content: Bytes) -> Result<()>
     where
         B: AsRef<str>,
         K: AsRef<str>,
+    {
+        self.put_file_with_meta(bucket, key, content, &HashMap::new())
+            .await
+    }
+
+    /// Upload `content`, attaching `meta` as `x-upyun-meta-*` headers
+    pub async fn put_file_with_meta<B, K>(
+        &self,
+        bucket: B,
+        key: K,
+        content: Bytes,
+        meta: &HashMap<String, String>,
+    ) -> Result<()>
+    where
+        B: AsRef<str>,
+        K: AsRef<str>,
+    {
+        let length = content.len();
+        let content = stream::iter(vec![Ok(content)]);
+        self.put_stream(bucket, key, content, Some(length), meta)
+            .await
+    }
+
+    /// Upload `content` as it arrives, attaching `meta` as `x-upyun-meta-*` headers
+    ///
+    /// Content above the multipart threshold goes through the multipart protocol, where
+    /// every part carries its own `Content-MD5`. Smaller content already in memory is sent
+    /// with a signed `Content-MD5` of the whole object, and streamed without one otherwise,
+    /// callers check the stored size and checksum afterwards. The multipart protocol needs
+    /// the total length up front, so content of unknown `length` is collected first.
+    pub async fn put_stream<B, K, S>(
+        &self,
+        bucket: B,
+        key: K,
+        content: S,
+        length: Option<usize>,
+        meta: &HashMap<String, String>,
+    ) -> Result<()>
+    where
+        B: AsRef<str>,
+        K: AsRef<str>,
+        S: Stream<Item = io::Result<Bytes>> + Send + Sync + 'static,
     {
         let path = format!("/{}/{}", bucket.as_ref(), key.as_ref());
-        let resp = self
+        let req = match peek_whole(content, length).await? {
+            Content::Whole(content) if content.len() > self.multipart_threshold => {
+                let length = content.len();
+                let content = stream::iter(vec![Ok(content)]);
+                return self.put_multipart(&path, content, length, meta).await;
+            }
+            Content::Whole(content) => {
+                let md5 = md5_hex(&content);
+                self.operator
+                    .signed_request(Method::PUT, self.provider(), path, None, Some(&md5))
+                    .body(content)
+            }
+            Content::Streaming(content) => {
+                let length = length.unwrap_or_default();
+                if length > self.multipart_threshold {
+                    return self.put_multipart(&path, content, length, meta).await;
+                }
+                self.operator
+                    .signed_request(Method::PUT, self.provider(), path, None, None)
+                    .header(header::CONTENT_LENGTH, length)
+                    .body(Body::wrap_stream(content))
+            }
+        };
+        self.check(self.send(with_meta(req, meta)).await?).await?;
+        Ok(())
+    }
+
+    /// Upload `length` bytes of `content` in 1 MiB parts with the parallel multipart protocol
+    ///
+    /// Parts are sent as soon as they are filled. A failed part is retried on its own
+    /// instead of the whole file.
+    /// https://help.upyun.com/knowledge-base/rest_api/#e5b9b6e8a18ce5bc8fe696ade782b9e7bbade4bca0
+    async fn put_multipart<S>(
+        &self,
+        path: &str,
+        content: S,
+        length: usize,
+        meta: &HashMap<String, String>,
+    ) -> Result<()>
+    where
+        S: Stream<Item = io::Result<Bytes>>,
+    {
+        let req = self
             .operator
-            .request(Method::PUT, self.provider, path, None)
-            .body(content)
-            .send()
+            .request(Method::PUT, self.provider(), path, None)
+            .header("x-upyun-multi-disorder", "true")
+            .header("x-upyun-multi-stage", "initiate")
+            .header("x-upyun-multi-type", "application/octet-stream")
+            .header("x-upyun-multi-length", length);
+        let resp = self.check(self.send(with_meta(req, meta)).await?).await?;
+        let uuid = resp
+            .headers()
+            .get("x-upyun-multi-uuid")
+            .and_then(|v| v.to_str().ok())
+            .ok_or(Error::MissingHeader("x-upyun-multi-uuid"))?
+            .to_string();
+        debug!("{} multipart upload {} initiated", path, uuid);
+
+        let uuid_ref = &uuid;
+        parts(content, PART_SIZE)
+            .enumerate()
+            .map(|(id, part)| async move { self.put_part(path, uuid_ref, id, part?).await })
+            .buffer_unordered(PART_CONCURRENCY)
+            .try_collect::<Vec<()>>()
             .await?;
-        match resp.status() {
-            StatusCode::OK => Ok(()),
-            _ => {
-                let err: Value = resp.json().await.unwrap();
-                Err(Error::Upyun(UpyunError::from(
-                    err["code"].as_u64().unwrap_or(0),
-                )))
+
+        let req = self
+            .operator
+            .request(Method::PUT, self.provider(), path, None)
+            .header("x-upyun-multi-stage", "complete")
+            .header("x-upyun-multi-uuid", &uuid);
+
+        let resp = self.send(req).await?;
+        self.check(resp).await?;
+        debug!("{} multipart upload {} completed", path, uuid);
+        Ok(())
+    }
+
+    async fn put_part(&self, path: &str, uuid: &str, id: usize, part: Bytes) -> Result<()> {
+        let md5 = md5_hex(&part);
+        let mut attempt = 1;
+        loop {
+            let req = self
+                .operator
+                .signed_request(Method::PUT, self.provider(), path, None, Some(&md5))
+                .header("x-upyun-multi-stage", "upload")
+                .header("x-upyun-multi-uuid", uuid)
+                .header("x-upyun-part-id", id)
+                .body(part.clone());
+            let resp = self.send(req).await;
+            let result = match resp {
+                Ok(resp) => self.check(resp).await.map(|_| ()),
+                Err(e) => Err(e),
+            };
+            match result {
+                Err(Error::Upyun(ref e)) if e.code() == PART_COMPLETED => return Ok(()),
+                Err(e) if e.is_retryable() && attempt < PART_ATTEMPTS => {
+                    let delay = retry::backoff(attempt);
+                    warn!(
+                        "{} part {} attempt {} failed: {}, retry in {:?}",
+                        path, id, attempt, e, delay
+                    );
+                    attempt += 1;
+                    tokio::time::sleep(delay).await;
+                }
+                result => return result,
             }
         }
     }
+
+    /// Merge `meta` into the metadata of an existing file
+    pub async fn update_meta<B, K>(
+        &self,
+        bucket: B,
+        key: K,
+        meta: &HashMap<String, String>,
+    ) -> Result<()>
+    where
+        B: AsRef<str>,
+        K: AsRef<str>,
+    {
+        let path = format!("/{}/{}?metadata=merge", bucket.as_ref(), key.as_ref());
+        let req = self
+            .operator
+            .request(Method::PATCH, self.provider(), path, None);
+        self.check(self.send(with_meta(req, meta)).await?).await?;
+        Ok(())
+    }
+
+    /// Returns `None` if the file does not exist
+    pub async fn head_file<B, K>(&self, bucket: B, key: K) -> Result<Option<FileInfo>>
+    where
+        B: AsRef<str>,
+        K: AsRef<str>,
+    {
+        let path = format!("/{}/{}", bucket.as_ref(), key.as_ref());
+        let req = self
+            .operator
+            .request(Method::HEAD, self.provider(), path, None);
+        let resp = self.send(req).await?;
+        if resp.status() == reqwest::StatusCode::NOT_FOUND {
+            return Ok(None);
+        }
+        let resp = self.check(resp).await?;
+        let headers = resp.headers();
+        let get = |name: &str| {
+            headers
+                .get(name)
+                .and_then(|v| v.to_str().ok())
+                .map(str::to_string)
+        };
+        let meta = headers
+            .iter()
+            .filter_map(|(name, value)| {
+                let key = name.as_str().strip_prefix(META_PREFIX)?;
+                Some((key.to_string(), value.to_str().ok()?.to_string()))
+            })
+            .collect();
+        Ok(Some(FileInfo {
+            file_type: get("x-upyun-file-type").unwrap_or_else(|| "file".to_string()),
+            size: get("x-upyun-file-size")
+                .and_then(|s| s.parse().ok())
+                .unwrap_or_default(),
+            date: get("x-upyun-file-date")
+                .and_then(|s| s.parse().ok())
+                .map(|ts| DateTime::from_utc(NaiveDateTime::from_timestamp(ts, 0), Utc)),
+            content_type: get(header::CONTENT_TYPE.as_str()),
+            content_md5: get("content-md5"),
+            meta,
+        }))
+    }
+
+    /// Download a file as a stream of chunks
+    pub async fn get_file<B, K>(
+        &self,
+        bucket: B,
+        key: K,
+    ) -> Result<impl Stream<Item = Result<Bytes>>>
+    where
+        B: AsRef<str>,
+        K: AsRef<str>,
+    {
+        let path = format!("/{}/{}", bucket.as_ref(), key.as_ref());
+        let req = self
+            .operator
+            .request(Method::GET, self.provider(), path, None);
+        let resp = self.send(req).await?;
+        Ok(self
+            .check(resp)
+            .await?
+            .bytes_stream()
+            .map(|chunk| chunk.map_err(Error::from)))
+    }
+
+    pub async fn delete_file<B, K>(&self, bucket: B, key: K) -> Result<()>
+    where
+        B: AsRef<str>,
+        K: AsRef<str>,
+    {
+        let path = format!("/{}/{}", bucket.as_ref(), key.as_ref());
+        let req = self
+            .operator
+            .request(Method::DELETE, self.provider(), path, None);
+        let resp = self.send(req).await?;
+        self.check(resp).await?;
+        Ok(())
+    }
+
+    /// List one page of `dir`, pass the `iter` of the previous page to continue
+    pub async fn list_dir<B, D>(
+        &self,
+        bucket: B,
+        dir: D,
+        iter: Option<&str>,
+        limit: usize,
+    ) -> Result<ListPage>
+    where
+        B: AsRef<str>,
+        D: AsRef<str>,
+    {
+        let dir = dir.as_ref().trim_matches('/');
+        let path = if dir.is_empty() {
+            format!("/{}/", bucket.as_ref())
+        } else {
+            format!("/{}/{}/", bucket.as_ref(), dir)
+        };
+        let mut req = self
+            .operator
+            .request(Method::GET, self.provider(), path, None)
+            .header(header::ACCEPT, "application/json")
+            .header("x-list-limit", limit)
+            .header("x-list-order", "asc");
+        if let Some(iter) = iter {
+            req = req.header("x-list-iter", iter);
+        }
+        let resp = self.send(req).await?;
+        if resp.status() == reqwest::StatusCode::NOT_FOUND {
+            return Ok(ListPage {
+                files: Vec::new(),
+                iter: None,
+            });
+        }
+        let ListResponse { files, iter } = self.check(resp).await?.json().await?;
+        Ok(ListPage {
+            files,
+            iter: iter.filter(|iter| iter != LIST_EOF),
+        })
+    }
+}
+
+fn with_meta(mut req: RequestBuilder, meta: &HashMap<String, String>) -> RequestBuilder {
+    for (key, value) in meta {
+        req = req.header(format!("{}{}", META_PREFIX, key).as_str(), value);
+    }
+    req
+}
+
+fn md5_hex<D: AsRef<[u8]>>(data: D) -> String {
+    hex::encode(Md5::digest(data.as_ref()))
 }
 
 fn format_gmt(date: DateTime<Utc>) -> String {
     format!("{}", date.format("%a, %d %b %Y %H:%M:%S GMT"))
 }
+
+/// A url for `uri` on `domain` carrying an anti-leech token valid for `ttl`
+///
+/// `secret` is the token secret configured for the domain, not the operator password.
+/// http://docs.upyun.com/cdn/feature/#token
+pub fn token_url(domain: &str, secret: &str, uri: &str, ttl: Duration) -> String {
+    let etime = (Utc::now() + ttl).timestamp();
+    format!("https://{}{}?_upt={}", domain, uri, upt(secret, etime, uri))
+}
+
+/// `_upt = MD5(secret&etime&URI)[12..20] + etime`
+fn upt(secret: &str, etime: i64, uri: &str) -> String {
+    let sign = md5_hex(format!("{}&{}&{}", secret, etime, uri));
+    format!("{}{}", &sign[12..20], etime)
+}
+
+#[test]
+fn test_sign() {
+    let operator = Operator::new("operator", "password");
+    let date = "Wed, 29 Oct 2014 02:26:58 GMT";
+    assert_eq!(
+        operator.sign(&Method::PUT, "/bucket/key", date, None, None),
+        "UPYUN operator:TwHe8VOec2DKn8dd8FyieOs79lU="
+    );
+    assert_eq!(
+        operator.sign(
+            &Method::PUT,
+            "/bucket/key",
+            date,
+            None,
+            Some(&md5_hex("abcdefg"))
+        ),
+        "UPYUN operator:pzWjEv65Jr3rMDZkJWwOw3E8Yos="
+    );
+}
+
+#[test]
+fn test_upt() {
+    assert_eq!(
+        upt("secret", 1600000000, "/serde/1.0.0"),
+        "e237a2611600000000"
+    );
+}
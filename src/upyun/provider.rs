@@ -2,7 +2,7 @@ use std::convert::From;
 
 use reqwest::Url;
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
 pub enum Provider {
     Auto,
     ChinaNet,
@@ -10,6 +10,24 @@ pub enum Provider {
     ChinaMobile,
 }
 
+impl Provider {
+    pub const ALL: [Provider; 4] = [
+        Provider::Auto,
+        Provider::ChinaNet,
+        Provider::Unicom,
+        Provider::ChinaMobile,
+    ];
+
+    pub fn index(self) -> usize {
+        match self {
+            Provider::Auto => 0,
+            Provider::ChinaNet => 1,
+            Provider::Unicom => 2,
+            Provider::ChinaMobile => 3,
+        }
+    }
+}
+
 impl From<Provider> for &'static str {
     fn from(provider: Provider) -> &'static str {
         match provider {
@@ -29,7 +47,7 @@ impl From<Provider> for Url {
 
 impl AsRef<str> for Provider {
     fn as_ref(&self) -> &'static str {
-        self.clone().into()
+        (*self).into()
     }
 }
 
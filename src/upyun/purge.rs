@@ -0,0 +1,65 @@
+//! Refresh CDN caches of urls served from a bucket
+//!
+//! https://help.upyun.com/knowledge-base/cdn_purge_api/
+use reqwest::header;
+use serde::Deserialize;
+
+use super::error::Result;
+use super::{format_gmt, md5_hex, Operator, Upyun, CLIENT};
+
+const PURGE_API: &str = "https://purge.upyun.com/purge/";
+
+/// Most urls purged in one request
+pub const MAX_PURGE_URLS: usize = 100;
+
+#[derive(Deserialize)]
+struct PurgeResponse {
+    #[serde(default)]
+    invalid_domain_of_url: Vec<String>,
+}
+
+impl Operator {
+    /// `UpYun {bucket}:{operator}:{md5(urls&bucket&date&md5(password))}`
+    fn purge_authorization(&self, bucket: &str, urls: &str, date: &str) -> String {
+        let sign = md5_hex(format!("{}&{}&{}&{}", urls, bucket, date, self.secret));
+        format!("UpYun {}:{}:{}", bucket, self.name, sign)
+    }
+}
+
+impl Upyun {
+    /// Purge up to `MAX_PURGE_URLS` urls on domains bound to `bucket`,
+    /// returns the urls Upyun refused as not on such a domain
+    pub async fn purge<B>(&self, bucket: B, urls: &[String]) -> Result<Vec<String>>
+    where
+        B: AsRef<str>,
+    {
+        let urls = urls.join("\n");
+        let date = format_gmt(self.operator.now());
+        let authorization = self
+            .operator
+            .purge_authorization(bucket.as_ref(), &urls, &date);
+        let req = CLIENT
+            .post(PURGE_API)
+            .header(header::AUTHORIZATION, authorization)
+            .header(header::DATE, date)
+            .form(&[("purge", &urls)]);
+        let resp = self.check(req.send().await?).await?;
+        let PurgeResponse {
+            invalid_domain_of_url,
+        } = resp.json().await?;
+        Ok(invalid_domain_of_url)
+    }
+}
+
+#[test]
+fn test_purge_authorization() {
+    let operator = Operator::new("operator", "password");
+    assert_eq!(
+        operator.purge_authorization(
+            "bucket",
+            "https://cdn.example.com/serde/1.0.0",
+            "Wed, 29 Oct 2014 02:26:58 GMT"
+        ),
+        "UpYun bucket:operator:191d4bee638cf82d5b0508d3fa8dffab"
+    );
+}